@@ -52,6 +52,17 @@ impl ArpHandler {
         }
     }
 
+    /// 停止识别符以prefix开头的所有琶音器，琶音任务在停止前会松开它最后按下的音符
+    pub fn stop_tasks_with_prefix(&self, prefix: &str) {
+        let mut arp_tasks = self.arp_tasks.lock().unwrap();
+        let identifiers: Vec<String> = arp_tasks.keys().filter(|id| id.starts_with(prefix)).cloned().collect();
+        for identifier in identifiers {
+            if let Some(tx) = arp_tasks.remove(&identifier) {
+                let _ = tx.send(());
+            }
+        }
+    }
+
 }
 
 fn send_midi_on(note: i8, velocity: i8, channel: i8) {
@@ -5,16 +5,19 @@ use lazy_static::lazy_static;
 use crate::message::Message;
 use crate::message::Message::Chord;
 use crate::midi_connect::GLOBAL_MIDI_CONNECTOR;
+use crate::notice::Notice;
 use crate::pulse_generator::PulseGenerator;
 
 pub struct ChordHandler {
-    chord_tasks: Mutex<HashMap<String, tokio::sync::oneshot::Sender<()>>>,
+    // 和弦识别符到它的关闭通道以及开启它的消息，关闭时需要根据消息计算出要松开的音符
+    chord_tasks: Mutex<HashMap<String, (tokio::sync::oneshot::Sender<()>, Message)>>,
 }
 
 impl ChordHandler {
-    pub fn handle(&self, identifier: String, message: Message) {
+    pub fn handle(&self, identifier: String, message: Message) -> Result<(), Notice> {
         // if let Chord { note, velocity, state, chord_type, chord_level, transpose, arp_delay } = message {
-        if let Chord { state,  .. } = message {
+        if let Chord { state, chord_type, chord_level, .. } = message {
+            validate_chord(chord_type, chord_level)?;
             if state == 1 {
                 // 开启和弦任务
                 self.start_chord_task(identifier, message);
@@ -24,6 +27,7 @@ impl ChordHandler {
                 self.stop_chord_task(identifier, message);
             }
         }
+        Ok(())
     }
 
     fn start_chord_task(&self, identifier: String, message: Message) {
@@ -41,6 +45,7 @@ impl ChordHandler {
             let pulse_generator = PulseGenerator::new(vec![Duration::from_secs_f64(_note_interval)]);
 
             let (stop_sender, mut stop_receiver) = tokio::sync::oneshot::channel();
+            let start_message = message.clone();
             tokio::task::spawn_blocking(move || {
                 for i in pulse_generator {
                     if let Ok(()) = stop_receiver.try_recv() { break; }
@@ -52,13 +57,13 @@ impl ChordHandler {
                 }
             });
 
-            self.chord_tasks.lock().unwrap().insert(identifier, stop_sender);
+            self.chord_tasks.lock().unwrap().insert(identifier, (stop_sender, start_message));
         }
     }
 
     fn stop_chord_task(&self, identifier: String, message: Message) {
         if let Chord { note, chord_type, chord_level, transpose, channel, ..} = message {
-            if let Some(tx) = self.chord_tasks.lock().unwrap().remove(&identifier) {
+            if let Some((tx, _)) = self.chord_tasks.lock().unwrap().remove(&identifier) {
                 let _  = tx.send(());
             }
             let mut note_offs = build_note_offsets(chord_type, chord_level);
@@ -66,6 +71,17 @@ impl ChordHandler {
             send_midi_off(note, note_offs, channel);
         }
     }
+
+    /// 停止识别符以prefix开头的所有和弦，并松开这些和弦中的音符
+    pub fn stop_tasks_with_prefix(&self, prefix: &str) {
+        let tasks: Vec<(String, Message)> = self.chord_tasks.lock().unwrap().iter()
+            .filter(|(id, _)| id.starts_with(prefix))
+            .map(|(id, (_, message))| (id.clone(), message.clone()))
+            .collect();
+        for (identifier, message) in tasks {
+            self.stop_chord_task(identifier, message);
+        }
+    }
 }
 
 fn send_midi_note_msg_once(note: i8, velocity: i8, state: i8, channel: i8) {
@@ -103,6 +119,17 @@ fn transpose_vec_once(note_offs: &mut Vec<i8>) {
 
 const CHORD_LEVEL_OFFS: [i8; 7] = [0, 4, 7, 11, 14, 17, 21];
 
+fn validate_chord(chord_type: i8, chord_level: i8) -> Result<(), Notice> {
+    if !(CHORD_TYPE_MAJOR..=CHORD_TYPE_ADD9).contains(&chord_type) {
+        return Err(Notice::InvalidChordType(chord_type));
+    }
+    // 和弦最多包含CHORD_LEVEL_OFFS中的全部音
+    if chord_level < 0 || 3 + chord_level as usize > CHORD_LEVEL_OFFS.len() {
+        return Err(Notice::InvalidChordLevel(chord_level));
+    }
+    Ok(())
+}
+
 fn build_note_offsets(chord_type: i8, chord_level: i8) -> Vec<i8> {
     let mut n = CHORD_LEVEL_OFFS.clone();
     match chord_type {
@@ -0,0 +1,350 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use lazy_static::lazy_static;
+use tokio::sync::{mpsc, oneshot};
+use crate::message::Message;
+use crate::permission::{default_role, Role};
+use crate::udp_server::SeqWindow;
+
+/// 客户端注册表，记录所有已连接的客户端，它全局唯一
+/// 连接建立时注册，断开时注销。通过它可以向某个客户端推送消息、踢掉某个客户端
+/// 客户端的设置按握手时的名字保存，断开重连后会被恢复
+pub struct ClientRegistry {
+    clients: HashMap<u64, ClientEntry>,
+    settings: HashMap<String, ClientSettings>,
+    next_id: u64,
+}
+
+struct ClientEntry {
+    info: ClientInfo,
+    // 该客户端写任务的消息通道
+    sender: mpsc::Sender<Message>,
+    kick: Option<oneshot::Sender<()>>,
+    // 最近一次发出的、还没有收到回复的Ping
+    pending_ping: Option<(i16, Instant)>,
+    next_ping_seq: i16,
+    // 客户端发送过Ping或者回复过Pong，说明它认识心跳消息，服务端才会给它发送Ping
+    heartbeat: bool,
+    // UDP通道的session以及它的去重窗口，客户端没有开启UDP通道时为None
+    udp: Option<(u32, SeqWindow)>,
+}
+
+/// 客户端的状态快照
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    // 握手之前为None
+    pub name: Option<String>,
+    pub connected_since: SystemTime,
+    pub last_activity: SystemTime,
+    // 正在运行的琶音和和弦，按根音记录
+    pub active_arps: BTreeSet<i8>,
+    pub active_chords: BTreeSet<i8>,
+    // 通过Midi Message按下还没有松开的音符，(channel, note)
+    pub held_notes: BTreeSet<(i8, i8)>,
+    // 弯音轮不在中间位置的通道
+    pub bent_channels: BTreeSet<i8>,
+    // 最近一次Ping的往返时延，客户端还没有回复过Ping时为None
+    pub rtt: Option<Duration>,
+    // 连接时为默认角色，可以在控制台中修改，断开后不保留
+    pub role: Role,
+}
+
+/// 跟随客户端名字保存的设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSettings {
+    pub daw: String,
+}
+
+impl ClientRegistry {
+    pub fn new() -> ClientRegistry {
+        ClientRegistry {
+            clients: HashMap::new(),
+            settings: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    /// 注册一个新连接，返回客户端id和被踢掉时会收到通知的接收端
+    pub fn register(&mut self, addr: SocketAddr, sender: mpsc::Sender<Message>) -> (u64, oneshot::Receiver<()>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (kick_tx, kick_rx) = oneshot::channel();
+        let now = SystemTime::now();
+        self.clients.insert(id, ClientEntry {
+            info: ClientInfo {
+                id, addr,
+                name: None,
+                connected_since: now,
+                last_activity: now,
+                active_arps: BTreeSet::new(),
+                active_chords: BTreeSet::new(),
+                held_notes: BTreeSet::new(),
+                bent_channels: BTreeSet::new(),
+                rtt: None,
+                role: default_role(),
+            },
+            sender,
+            kick: Some(kick_tx),
+            pending_ping: None,
+            next_ping_seq: 0,
+            heartbeat: false,
+            udp: None,
+        });
+        (id, kick_rx)
+    }
+
+    pub fn unregister(&mut self, id: u64) -> Option<ClientInfo> {
+        self.clients.remove(&id).map(|entry| entry.info)
+    }
+
+    pub fn touch(&mut self, id: u64) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            entry.info.last_activity = SystemTime::now();
+        }
+    }
+
+    /// 记录客户端握手时的名字，返回该名字上次连接时保存的设置
+    pub fn set_name(&mut self, id: u64, name: String) -> Option<ClientSettings> {
+        let settings = self.settings.get(&name).cloned();
+        if let Some(entry) = self.clients.get_mut(&id) {
+            entry.info.name = Some(name);
+        }
+        settings
+    }
+
+    /// 保存客户端的设置，还没有握手的客户端没有名字，不保存
+    pub fn save_settings(&mut self, id: u64, settings: ClientSettings) {
+        if let Some(name) = self.clients.get(&id).and_then(|entry| entry.info.name.clone()) {
+            self.settings.insert(name, settings);
+        }
+    }
+
+    pub fn set_role(&mut self, id: u64, role: Role) -> bool {
+        match self.clients.get_mut(&id) {
+            Some(entry) => {
+                entry.info.role = role;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// 客户端的角色，不在注册表中的客户端使用默认角色
+    pub fn role(&self, id: u64) -> Role {
+        self.clients.get(&id).map(|entry| entry.info.role).unwrap_or_else(default_role)
+    }
+
+    pub fn set_arp_active(&mut self, id: u64, note: i8, active: bool) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            set_active(&mut entry.info.active_arps, note, active);
+        }
+    }
+
+    pub fn set_chord_active(&mut self, id: u64, note: i8, active: bool) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            set_active(&mut entry.info.active_chords, note, active);
+        }
+    }
+
+    pub fn set_note_held(&mut self, id: u64, channel: i8, note: i8, held: bool) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            set_active(&mut entry.info.held_notes, (channel, note), held);
+        }
+    }
+
+    pub fn set_pitch_bent(&mut self, id: u64, channel: i8, bent: bool) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            set_active(&mut entry.info.bent_channels, channel, bent);
+        }
+    }
+
+    pub fn enable_heartbeat(&mut self, id: u64) {
+        if let Some(entry) = self.clients.get_mut(&id) {
+            entry.heartbeat = true;
+        }
+    }
+
+    pub fn supports_heartbeat(&self, id: u64) -> bool {
+        self.clients.get(&id).map(|entry| entry.heartbeat).unwrap_or(false)
+    }
+
+    /// 记录一次发给客户端的Ping，返回它的序号。上一个Ping还没有回复时会被覆盖
+    /// 不认识心跳消息的旧客户端不发送Ping，返回None
+    pub fn start_ping(&mut self, id: u64) -> Option<i16> {
+        let entry = self.clients.get_mut(&id).filter(|entry| entry.heartbeat)?;
+        let seq = entry.next_ping_seq;
+        entry.next_ping_seq = seq.wrapping_add(1);
+        entry.pending_ping = Some((seq, Instant::now()));
+        Some(seq)
+    }
+
+    /// 客户端回复了Pong，序号与最近一次Ping一致时更新往返时延
+    pub fn finish_ping(&mut self, id: u64, seq: i16) -> Option<Duration> {
+        let entry = self.clients.get_mut(&id)?;
+        match entry.pending_ping {
+            Some((pending_seq, sent_at)) if pending_seq == seq => {
+                entry.pending_ping = None;
+                entry.info.rtt = Some(sent_at.elapsed());
+                entry.info.rtt
+            }
+            _ => None
+        }
+    }
+
+    /// 为客户端开启UDP通道，返回一个随机的、非0的session
+    pub fn open_udp_session(&mut self, id: u64) -> Option<u32> {
+        let mut session = 0;
+        while session == 0 || self.clients.values().any(|entry| matches!(entry.udp, Some((s, _)) if s == session)) {
+            session = rand::random();
+        }
+        let entry = self.clients.get_mut(&id)?;
+        entry.udp = Some((session, SeqWindow::new()));
+        Some(session)
+    }
+
+    /// 校验一个UDP数据报，session存在、来源IP与TCP连接一致并且seq没有收到过时，返回客户端的id与TCP地址
+    pub fn accept_udp(&mut self, session: u32, ip: IpAddr, seq: u32) -> Option<(u64, SocketAddr)> {
+        let entry = self.clients.values_mut().find(|entry| matches!(entry.udp, Some((s, _)) if s == session))?;
+        if entry.info.addr.ip() != ip { return None; }
+        let (_, window) = entry.udp.as_mut()?;
+        if !window.accept(seq) { return None; }
+        entry.info.last_activity = SystemTime::now();
+        Some((entry.info.id, entry.info.addr))
+    }
+
+    /// MIDI panic之后，所有客户端的音符、琶音、和弦和弯音都已经被复位
+    pub fn clear_activity(&mut self) {
+        for entry in self.clients.values_mut() {
+            entry.info.active_arps.clear();
+            entry.info.active_chords.clear();
+            entry.info.held_notes.clear();
+            entry.info.bent_channels.clear();
+        }
+    }
+
+    pub fn client(&self, id: u64) -> Option<ClientInfo> {
+        self.clients.get(&id).map(|entry| entry.info.clone())
+    }
+
+    /// 所有客户端的快照，按id排序
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self.clients.values().map(|entry| entry.info.clone()).collect();
+        clients.sort_by_key(|info| info.id);
+        clients
+    }
+
+    /// 向某个客户端推送消息，客户端不存在或者写通道已满时返回false
+    pub fn send_to(&self, id: u64, msg: Message) -> bool {
+        match self.clients.get(&id) {
+            Some(entry) => entry.sender.try_send(msg).is_ok(),
+            None => false
+        }
+    }
+
+    /// 踢掉某个客户端，连接会被关闭，注销由连接任务完成
+    pub fn kick(&mut self, id: u64) -> bool {
+        match self.clients.get_mut(&id).and_then(|entry| entry.kick.take()) {
+            Some(kick) => kick.send(()).is_ok(),
+            None => false
+        }
+    }
+}
+
+fn set_active<T: Ord>(set: &mut BTreeSet<T>, value: T, active: bool) {
+    if active { set.insert(value); } else { set.remove(&value); }
+}
+
+lazy_static! {
+    pub static ref GLOBAL_CLIENT_REGISTRY: Mutex<ClientRegistry> = Mutex::new(ClientRegistry::new());
+}
+
+#[cfg(test)]
+mod client_registry_test {
+    use std::net::SocketAddr;
+    use tokio::sync::{mpsc, oneshot};
+    use crate::client_registry::{ClientRegistry, ClientSettings};
+    use crate::message::Message;
+    use crate::permission::Role;
+
+    fn addr() -> SocketAddr {
+        "192.168.1.2:50000".parse().unwrap()
+    }
+
+    // 测试中不需要读取发给客户端的消息
+    fn register(registry: &mut ClientRegistry) -> (u64, oneshot::Receiver<()>) {
+        registry.register(addr(), mpsc::channel::<Message>(1).0)
+    }
+
+    #[test]
+    fn test_register_and_unregister() {
+        let mut registry = ClientRegistry::new();
+        let (first, mut kick_rx) = register(&mut registry);
+        let (second, _) = register(&mut registry);
+        assert_ne!(first, second);
+        assert_eq!(registry.role(first), Role::Full);
+        assert!(registry.set_role(first, Role::Notes));
+        assert_eq!(registry.client(first).unwrap().role, Role::Notes);
+        assert!(registry.kick(first));
+        assert!(kick_rx.try_recv().is_ok());
+        assert!(!registry.kick(first));
+
+        assert_eq!(registry.set_name(first, "iPad".into()), None);
+        registry.save_settings(first, ClientSettings { daw: "cubase".into() });
+        assert!(registry.unregister(first).is_some());
+        assert!(registry.client(first).is_none());
+        assert!(!registry.set_role(first, Role::Mixer));
+        assert_eq!(registry.clients()[0].id, second);
+        // 同名客户端重连后拿回之前的设置
+        let (id, _) = register(&mut registry);
+        assert_eq!(registry.set_name(id, "iPad".into()), Some(ClientSettings { daw: "cubase".into() }));
+    }
+
+    #[test]
+    fn test_state_left_on_unregister() {
+        let mut registry = ClientRegistry::new();
+        let (id, _) = register(&mut registry);
+        registry.set_arp_active(id, 60, true);
+        registry.set_arp_active(id, 64, true);
+        registry.set_arp_active(id, 60, false);
+        registry.set_note_held(id, 1, 60, true);
+        registry.set_note_held(id, 2, 60, true);
+        registry.set_note_held(id, 1, 60, false);
+        registry.set_pitch_bent(id, 1, true);
+        let info = registry.unregister(id).unwrap();
+        assert_eq!(info.active_arps.into_iter().collect::<Vec<i8>>(), vec![64]);
+        assert_eq!(info.held_notes.into_iter().collect::<Vec<(i8, i8)>>(), vec![(2, 60)]);
+        assert_eq!(info.bent_channels.into_iter().collect::<Vec<i8>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_ping() {
+        let mut registry = ClientRegistry::new();
+        let (id, _) = register(&mut registry);
+        assert!(registry.start_ping(id).is_none());
+        registry.enable_heartbeat(id);
+        let first = registry.start_ping(id).unwrap();
+        let second = registry.start_ping(id).unwrap();
+        assert_ne!(first, second);
+        // 过期的Pong被忽略
+        assert!(registry.finish_ping(id, first).is_none());
+        assert!(registry.finish_ping(id, second).is_some());
+        assert!(registry.finish_ping(id, second).is_none());
+        assert!(registry.client(id).unwrap().rtt.is_some());
+    }
+
+    #[test]
+    fn test_udp_session() {
+        let mut registry = ClientRegistry::new();
+        let (id, _) = register(&mut registry);
+        let session = registry.open_udp_session(id).unwrap();
+        assert_ne!(session, 0);
+        assert_eq!(registry.accept_udp(session, addr().ip(), 1), Some((id, addr())));
+        assert_eq!(registry.accept_udp(session, addr().ip(), 1), None);
+        assert_eq!(registry.accept_udp(session, "192.168.1.3".parse().unwrap(), 2), None);
+        assert_eq!(registry.accept_udp(session.wrapping_add(1), addr().ip(), 3), None);
+    }
+}
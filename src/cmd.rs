@@ -1,13 +1,24 @@
 use std::io::stdin;
-use std::net::{IpAddr};
-use std::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::MutexGuard;
 use std::env;
-use network_interface::{NetworkInterface, NetworkInterfaceConfig};
-use crate::constants;
-use crate::midi_connect::{GLOBAL_CTL_CONNECTOR, GLOBAL_MIDI_CONNECTOR, MidiConnector};
+use std::path::PathBuf;
+use crate::{console, constants};
+use crate::config::{DawConfig, default_config_path, load_config, MidiConfig, save_config, SecurityConfig, ServerConfig, VPadConfig};
+use crate::control_handler::set_default_daw;
+use crate::daw_profile::{default_profile_dir, load_profile_dir, profile_exists, profile_names};
+use crate::daw_state::on_control_feedback;
+use crate::discovery::vaild_ipv4_interfaces;
+use crate::midi_connect::{GLOBAL_CTL_CONNECTOR, GLOBAL_CTL_INPUT_CONNECTOR, GLOBAL_MIDI_CONNECTOR, MidiConnector, MidiInputConnector, set_default_channel};
+use crate::pairing::{default_paired_clients_path, enable_pairing, pairing_pin};
+use crate::permission::{Role, set_default_role};
+use crate::scale::{Scale, set_scale};
+use crate::tempo::{set_tempo_source, TempoSource};
 use crate::server;
-use clap::Parser;
+use crate::tls::{default_tls_dir, enable_tls, tls_fingerprint};
+use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
+use log::LevelFilter;
 
 #[derive(Parser)]
 #[command(name = "VPadCore")]
@@ -15,12 +26,97 @@ use clap::Parser;
 #[command(version = "1.0")]
 #[command(author, version)]
 struct CoreCli {
+	/// 配置文件，默认为用户配置目录下的vpad/config.toml，命令行参数优先于配置文件
+	#[arg(long)]
+	config: Option<PathBuf>,
+	/// 乐器MIDI输出端口，配置文件中没有时必须指定
 	#[arg(short)]
-	instrument_midi_port: String,
+	instrument_midi_port: Option<String>,
+	/// 控制MIDI输出端口，配置文件中没有时必须指定
 	#[arg(short)]
-	control_midi_port: String,
-	#[arg(short)]
-	log_level: Option<String>
+	control_midi_port: Option<String>,
+	/// 接收DAW反馈的MIDI输入端口，默认与控制端口同名
+	#[arg(short = 'f')]
+	control_feedback_midi_port: Option<String>,
+	/// 日志级别：off、error、warn、info、debug或trace，默认使用log4rs-config.yaml中的级别
+	#[arg(short, long)]
+	log_level: Option<LevelFilter>,
+	/// 客户端消息中channel为0时使用的MIDI通道，默认为1
+	#[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+	channel: Option<u8>,
+	/// 把客户端的音符对齐到音阶内，比如"C major"、"F# minor"，默认为chromatic，不做对齐
+	#[arg(long)]
+	scale: Option<Scale>,
+	/// Arp与Chord的速度来源：client使用客户端给出的bpm，daw使用DAW发到反馈端口的MIDI时钟，默认为client
+	#[arg(long)]
+	tempo_source: Option<TempoSource>,
+	/// 客户端没有指定DAW时使用的DAW配置，可以是内置配置或配置目录中的自定义配置
+	#[arg(short, long)]
+	daw: Option<String>,
+	/// DAW配置目录，默认为用户配置目录下的vpad/profiles
+	#[arg(long)]
+	profile_dir: Option<PathBuf>,
+	/// WebSocket监听端口，供浏览器中的客户端连接，不指定时不开启
+	#[arg(long)]
+	ws_port: Option<u16>,
+	/// 开启UDP广播发现，回复局域网内客户端的探测包，不指定端口时使用1238
+	#[arg(long, num_args = 0..=1, default_missing_value = "1238")]
+	discovery: Option<u16>,
+	/// 开启配对，客户端必须出示启动时显示的PIN或者之前配对时拿到的密钥才能连接
+	#[arg(long)]
+	pairing: bool,
+	/// TCP连接使用TLS加密，第一次开启时在用户配置目录下的vpad/tls中生成自签名证书
+	#[arg(long)]
+	tls: bool,
+	/// 新连接的客户端的角色：notes只能演奏，mixer额外可以控制调音台，full可以控制一切，默认为full
+	#[arg(long)]
+	role: Option<Role>,
+	/// 监听地址，可以指定多次同时监听多个地址，比如0.0.0.0和::，默认为0.0.0.0
+	#[arg(long)]
+	bind: Vec<IpAddr>,
+	/// TCP端口，被占用时依次尝试后面的端口，默认为1236
+	#[arg(short, long)]
+	port: Option<u16>,
+	/// TCP监听队列长度，默认为1024
+	#[arg(long)]
+	backlog: Option<u32>,
+	/// IPv6监听地址同时接受IPv4连接，不能与同端口的IPv4监听地址同时使用
+	#[arg(long)]
+	dual_stack: bool
+}
+
+impl CoreCli {
+	/// 命令行中给出的值，用来覆盖配置文件，开关类参数只有给出时才覆盖
+	fn to_config(&self) -> VPadConfig {
+		VPadConfig {
+			log_level: self.log_level,
+			channel: self.channel,
+			scale: self.scale,
+			tempo_source: self.tempo_source,
+			midi: MidiConfig {
+				instrument_port: self.instrument_midi_port.clone(),
+				control_port: self.control_midi_port.clone(),
+				feedback_port: self.control_feedback_midi_port.clone(),
+			},
+			server: ServerConfig {
+				bind: self.bind.clone(),
+				port: self.port,
+				backlog: self.backlog,
+				dual_stack: self.dual_stack.then_some(true),
+				ws_port: self.ws_port,
+				discovery_port: self.discovery,
+			},
+			daw: DawConfig {
+				profile: self.daw.clone(),
+				profile_dir: self.profile_dir.clone(),
+			},
+			security: SecurityConfig {
+				pairing: self.pairing.then_some(true),
+				tls: self.tls.then_some(true),
+				role: self.role,
+			},
+		}
+	}
 }
 
 const SLOGAN: &str = r"
@@ -34,20 +130,27 @@ ____   ______________             .___ __________                __
 pub async fn startup() {
 	if env::args().len() > 1 {
 		// core mode
-		println!("core mode!");
 		let cli = CoreCli::parse();
-		let midi_connector = GLOBAL_MIDI_CONNECTOR.lock().unwrap();
-		let ctl_connector = GLOBAL_CTL_CONNECTOR.lock().unwrap();
-		println!("Trying to connect to {}", &cli.instrument_midi_port);
-		connect_to_a_midi_port(midi_connector, cli.instrument_midi_port);
-		println!("Trying to connect to {}", &cli.control_midi_port);
-		connect_to_a_midi_port(ctl_connector, cli.control_midi_port);
-		start_server().await;
+		let config = load_config_or_default(cli.config.clone()).merge(cli.to_config());
+		init_logging(config.log_level);
+		println!("core mode!");
+		if config.midi.instrument_port.is_none() || config.midi.control_port.is_none() {
+			CoreCli::command().error(ErrorKind::MissingRequiredArgument,
+				"instrument and control midi ports are required, give them with -i and -c or in the [midi] section of the config file").exit();
+		}
+		apply_config(&config);
+		start_server(&config.server, false).await;
 	} else {
 		// standalone mode
+		let mut config = load_config_or_default(None);
+		init_logging(config.log_level);
 		print_slogan();
-		request_user_to_connect_midi_output_port();
-		start_server().await;
+		if request_user_to_complete_config(&mut config) {
+			request_user_to_save_config(&config);
+		}
+		apply_config(&config);
+		println!("\n\nAll Settings done! Enjoy it~");
+		start_server(&config.server, true).await;
 	}
 }
 
@@ -55,9 +158,57 @@ pub async fn startup() {
 // ================ Helper Functions ================== //
 
 
-async fn start_server() {
-	let vpad_server = server::VPadServer::bind(IpAddr::from_str("0.0.0.0").expect(""), 1236);
-	vpad_server.start().await.expect("Cannot start VPadServer.");
+/// 先监听端口再打印二维码，端口被占用而回退到其它端口时，二维码中是实际的端口
+async fn start_server(options: &ServerConfig, show_qrcode: bool) {
+	tokio::spawn(console::run_console());
+	let mut vpad_server = build_server(options);
+	let port = vpad_server.listen().expect("Cannot listen on VPadServer port.");
+	println!("VPadServer is listening on port {}", port);
+	// 只有TCP端口是加密的，WebSocket与网页控制器仍然是明文
+	if tls_fingerprint().is_some() && vpad_server.ws_port.is_some() {
+		log::warn!("TLS only encrypts the TCP port, the WebSocket port is still plain text");
+		println!("Warning: TLS only encrypts the TCP port, browsers on the WebSocket port are NOT encrypted.");
+	}
+	if show_qrcode {
+		print_qrcode(port, vpad_server.ws_port);
+	}
+	let handle = vpad_server.start().await.expect("Cannot start VPadServer.");
+	server::shutdown_signal().await;
+	println!("Shutting down...");
+	handle.shutdown().await;
+	// 控制台在阻塞线程中读取标准输入，这个读取无法取消，直接退出而不是等待运行时结束
+	std::process::exit(0);
+}
+
+fn build_server(options: &ServerConfig) -> server::VPadServer {
+	let mut addrs = options.bind.iter();
+	let first = addrs.next().copied().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+	let mut vpad_server = server::VPadServer::bind(first, options.port.unwrap_or(server::DEFAULT_PORT))
+		.with_dual_stack(options.dual_stack.unwrap_or(false));
+	for addr in addrs {
+		vpad_server = vpad_server.with_address(*addr);
+	}
+	if let Some(backlog) = options.backlog {
+		vpad_server = vpad_server.with_backlog(backlog);
+	}
+	if let Some(ws_port) = options.ws_port.filter(|port| *port != 0) {
+		vpad_server = vpad_server.with_websocket(ws_port);
+	}
+	if let Some(discovery_port) = options.discovery_port {
+		vpad_server = vpad_server.with_discovery(discovery_port);
+	}
+	vpad_server
+}
+
+/// 加载用户自定义的DAW配置，任何一个配置不合法都会崩溃，避免带着错误的映射运行
+fn load_daw_profiles(dir: Option<PathBuf>) {
+	if let Some(dir) = dir {
+		match load_profile_dir(&dir) {
+			Ok(loaded) if !loaded.is_empty() => println!("Loaded DAW profiles from {:?}: {}", dir, loaded.join(", ")),
+			Ok(_) => {}
+			Err(e) => panic!("{}", e)
+		}
+	}
 }
 
 fn print_slogan() {
@@ -73,19 +224,139 @@ fn connect_to_a_midi_port(mut connector: MutexGuard<MidiConnector>, port: String
 	}
 }
 
-fn select_a_port_and_connect(connector: MutexGuard<MidiConnector>, port_list: &Vec<String>) {
+/// 反馈端口不是必须的，连不上时只影响DAW状态的同步，所以这里不会崩溃
+fn connect_to_control_feedback_port(port: String) {
+	match GLOBAL_CTL_INPUT_CONNECTOR.lock().unwrap().connect_port(port, on_control_feedback) {
+		Ok(()) => println!("Listening to control feedback!"),
+		Err(e) => {
+			log::warn!("Cannot listen to control feedback port: {:?}", e);
+			println!("Cannot listen to control feedback port, DAW state will not be synchronized.");
+		}
+	}
+}
+
+/// 读取配置文件，没有用--config指定并且默认位置也没有配置文件时使用默认配置
+/// 配置文件不合法时崩溃，避免带着错误的配置运行
+fn load_config_or_default(path: Option<PathBuf>) -> VPadConfig {
+	let path = match path.or_else(|| default_config_path().filter(|path| path.exists())) {
+		Some(path) => path,
+		None => return VPadConfig::default()
+	};
+	match load_config(&path) {
+		Ok(config) => {
+			println!("Loaded config from {:?}", path);
+			config
+		}
+		Err(e) => panic!("{}", e)
+	}
+}
+
+/// 指定了日志级别时覆盖log4rs-config.yaml中root的级别，此时修改配置文件不会再自动生效
+fn init_logging(level: Option<LevelFilter>) {
+	match level {
+		Some(level) => {
+			let mut config = log4rs::load_config_file(LOG_CONFIG_FILE, Default::default()).unwrap();
+			config.root_mut().set_level(level);
+			log4rs::init_config(config).unwrap();
+		}
+		None => log4rs::init_file(LOG_CONFIG_FILE, Default::default()).unwrap()
+	}
+	log::info!("log4rs initialized!");
+}
+
+/// 按配置加载DAW配置、设置默认角色与演奏设置、连接MIDI端口，并开启配对与TLS
+fn apply_config(config: &VPadConfig) {
+	load_daw_profiles(config.daw.profile_dir.clone().or_else(default_profile_dir));
+	if let Some(daw) = &config.daw.profile {
+		if !profile_exists(daw) {
+			panic!("DAW profile [{}] does not exist. Available profiles: {}", daw, profile_names().join(", "));
+		}
+		set_default_daw(daw.clone());
+	}
+	if let Some(role) = config.security.role {
+		set_default_role(role);
+	}
+	if let Some(channel) = config.channel {
+		if !(1..=16).contains(&channel) {
+			panic!("Default channel must be between 1 and 16, got {}", channel);
+		}
+		set_default_channel(channel as i8);
+	}
+	if let Some(scale) = config.scale {
+		set_scale(scale);
+	}
+	if let Some(tempo_source) = config.tempo_source {
+		set_tempo_source(tempo_source);
+	}
+	let instrument_port = config.midi.instrument_port.clone().expect("instrument midi port is not given");
+	let control_port = config.midi.control_port.clone().expect("control midi port is not given");
+	println!("Trying to connect to {}", &instrument_port);
+	connect_to_a_midi_port(GLOBAL_MIDI_CONNECTOR.lock().unwrap(), instrument_port);
+	println!("Trying to connect to {}", &control_port);
+	connect_to_a_midi_port(GLOBAL_CTL_CONNECTOR.lock().unwrap(), control_port.clone());
+	let feedback_port = config.midi.feedback_port.clone().unwrap_or(control_port);
+	if !feedback_port.is_empty() {
+		println!("Trying to listen to {}", &feedback_port);
+		connect_to_control_feedback_port(feedback_port);
+	}
+	if config.security.pairing.unwrap_or(false) {
+		println!("Pairing PIN: {}", enable_pairing_or_panic());
+	}
+	if config.security.tls.unwrap_or(false) {
+		println!("TLS certificate fingerprint: {}", enable_tls_or_panic());
+	}
+}
+
+/// 只询问配置文件中没有的设置，返回是否询问过用户
+fn request_user_to_complete_config(config: &mut VPadConfig) -> bool {
+	let mut asked = false;
+	if config.midi.instrument_port.is_none() || config.midi.control_port.is_none() {
+		request_user_to_choose_midi_ports(&mut config.midi);
+		asked = true;
+	}
+	if config.server.ws_port.is_none() {
+		config.server.ws_port = Some(request_user_to_enable_web_pad().unwrap_or(0));
+		asked = true;
+	}
+	if config.security.pairing.is_none() {
+		config.security.pairing = Some(request_user_to_enable_pairing());
+		asked = true;
+	}
+	if config.security.tls.is_none() {
+		config.security.tls = Some(request_user_to_enable_tls());
+		asked = true;
+	}
+	asked
+}
+
+/// 保存后下次启动时不再询问，想重新选择时删除配置文件中对应的项即可
+fn request_user_to_save_config(config: &VPadConfig) {
+	let path = match default_config_path() {
+		Some(path) => path,
+		None => return
+	};
+	println!("\n\nSave these settings to {:?} so they are not asked next time? (y/N): ", path);
+	if !read_yes() { return; }
+	match save_config(&path, config) {
+		Ok(()) => println!("Settings saved!"),
+		Err(e) => println!("Cannot save settings: {}", e)
+	}
+}
+
+fn read_yes() -> bool {
+	let mut answer = String::new();
+	stdin().read_line(&mut answer).expect("Cannot read from stdin");
+	answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn select_a_port(port_list: &[String]) -> String {
 	let mut index = String::new();
 	stdin().read_line(&mut index).expect("Cannot read from stdin");
 	let index = index.trim().parse::<usize>().expect("Your input cannot convert to a index");
-	let selected_port_name = port_list.get(index - 1).expect(&format!("Cannot get index {}. Please it's not out of bounds", index)).clone();
-	println!("Trying to connect to [{}]", selected_port_name);
-	connect_to_a_midi_port(connector, selected_port_name);
+	port_list.get(index - 1).expect(&format!("Cannot get index {}. Please it's not out of bounds", index)).clone()
 }
 
-fn request_user_to_connect_midi_output_port() {
-	let midi_connector = GLOBAL_MIDI_CONNECTOR.lock().unwrap();
-	let ctl_connector = GLOBAL_CTL_CONNECTOR.lock().unwrap();
-
+fn request_user_to_choose_midi_ports(midi: &mut MidiConfig) {
 	// === print_output_ports_and_select_name
 	println!("Available midi output port: ");
 	let port_list = MidiConnector::port_list().expect("Cannot get midi port list");
@@ -94,38 +365,103 @@ fn request_user_to_connect_midi_output_port() {
 	}
 
 	println!("\n\nChoose instrument midi device: ");
-	select_a_port_and_connect(midi_connector, &port_list);
+	midi.instrument_port = Some(select_a_port(&port_list));
 
 	println!("\n\nChoose control midi device: ");
-	select_a_port_and_connect(ctl_connector, &port_list);
+	midi.control_port = Some(select_a_port(&port_list));
 
-	println!("\n\nAll Settings done! Enjoy it~");
+	midi.feedback_port = Some(request_user_to_choose_control_feedback_port().unwrap_or_default());
+}
+
+/// 跳过时返回None
+fn request_user_to_choose_control_feedback_port() -> Option<String> {
+	println!("\n\nAvailable midi input port: ");
+	let port_list = MidiInputConnector::port_list().expect("Cannot get midi input port list");
+	for i in 0..port_list.len() {
+		println!("\t{}. {}", i + 1, port_list[i]);
+	}
 
-	print_qrcode();
+	println!("\n\nChoose control feedback midi device (0 to skip): ");
+	let mut index = String::new();
+	stdin().read_line(&mut index).expect("Cannot read from stdin");
+	let index = index.trim().parse::<usize>().expect("Your input cannot convert to a index");
+	if index == 0 { return None; }
+	Some(port_list.get(index - 1).expect(&format!("Cannot get index {}. Please it's not out of bounds", index)).clone())
+}
+
+/// 网页控制器与WebSocket共用一个端口，开启后任何有浏览器的设备都可以扫码作为控制器
+fn request_user_to_enable_web_pad() -> Option<u16> {
+	println!("\n\nServe the web pad for browsers on port (0 to skip, empty for {}): ", DEFAULT_WEB_PORT);
+	let mut port = String::new();
+	stdin().read_line(&mut port).expect("Cannot read from stdin");
+	let port = port.trim();
+	if port.is_empty() { return Some(DEFAULT_WEB_PORT); }
+	match port.parse::<u16>().expect("Your input cannot convert to a port") {
+		0 => None,
+		port => Some(port)
+	}
+}
+
+/// 开启配对后，二维码中会带上PIN，扫码的客户端可以直接完成配对
+fn request_user_to_enable_pairing() -> bool {
+	println!("\n\nRequire clients to pair with a PIN? (y/N): ");
+	read_yes()
+}
+
+fn enable_pairing_or_panic() -> String {
+	match enable_pairing(default_paired_clients_path()) {
+		Ok(pin) => pin,
+		Err(e) => panic!("{}", e)
+	}
+}
+
+/// 开启TLS后，二维码中会带上证书指纹，客户端用它验证自签名证书
+fn request_user_to_enable_tls() -> bool {
+	println!("\n\nEncrypt connections with TLS? (y/N): ");
+	read_yes()
 }
 
+fn enable_tls_or_panic() -> String {
+	let dir = default_tls_dir().expect("Cannot find the config directory for tls certificate");
+	match enable_tls(&dir) {
+		Ok(fingerprint) => fingerprint,
+		Err(e) => panic!("{}", e)
+	}
+}
 
-/// 遍历每个网络接口，获取所有合法ip地址
-/// 	1. 必须是ipv4
-/// 	2. 必须不能是loopback
-/// 	3. 如果一个接口上有多个ip，取第一个符合的ip
+/// 所有合法ip地址，规则见discovery::vaild_ipv4_interfaces
 fn get_all_vaild_ip_addresses() -> Vec<String> {
-	NetworkInterface::show().expect("cannot get network interfaces").iter().filter_map(|iface| {
-		let addrs = &iface.addr;
-		for addr in addrs {
-			let ip = addr.ip();
-			if ip.is_ipv4() && !ip.is_loopback() {
-				return Some(ip.to_string())
-			}
-		}
-		None
-	}).collect()
+	vaild_ipv4_interfaces().iter().map(|iface| iface.ip.to_string()).collect()
 }
 
-fn print_qrcode() {
+/// 开启了网页控制器时，每个ip打印一个网页控制器地址的二维码，浏览器只能打开其中一个地址
+/// 否则是一个包含所有ip地址的二维码，供App扫码连接，App会依次尝试每个地址
+/// 端口不是默认的1236时，每个ip后面带上`:端口`，旧版本App只认识默认端口
+/// 开启配对或者TLS时二维码末尾带有`#pin=<PIN>&fp=<证书指纹>`
+fn print_qrcode(port: u16, web_port: Option<u16>) {
 	println!("There is your qrcode: ");
-	let qrcontent = get_all_vaild_ip_addresses().join(";");
-	if qrcontent.is_empty() { panic!("it seems there's no any network interface on your computer. so ... panic!"); }
-	qr2term::print_qr(qrcontent).expect("cannot print qrcode");
+	let ip_addresses = get_all_vaild_ip_addresses();
+	if ip_addresses.is_empty() { panic!("it seems there's no any network interface on your computer. so ... panic!"); }
+	let params: Vec<String> = [("pin", pairing_pin()), ("fp", tls_fingerprint())].into_iter()
+		.filter_map(|(key, value)| value.map(|value| format!("{}={}", key, value)))
+		.collect();
+	let fragment = if params.is_empty() { String::new() } else { format!("#{}", params.join("&")) };
+	if let Some(web_port) = web_port {
+		for ip in &ip_addresses {
+			let url = format!("http://{}:{}/{}", ip, web_port, fragment);
+			println!("Open {} in your browser", url);
+			qr2term::print_qr(url).expect("cannot print qrcode");
+		}
+		return;
+	}
+	let addresses: Vec<String> = if port == server::DEFAULT_PORT {
+		ip_addresses
+	} else {
+		ip_addresses.iter().map(|ip| format!("{}:{}", ip, port)).collect()
+	};
+	qr2term::print_qr(format!("{}{}", addresses.join(";"), fragment)).expect("cannot print qrcode");
 }
 
+const DEFAULT_WEB_PORT: u16 = 1237;
+const LOG_CONFIG_FILE: &str = "log4rs-config.yaml";
+
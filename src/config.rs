@@ -0,0 +1,258 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use crate::permission::Role;
+use crate::scale::Scale;
+use crate::tempo::TempoSource;
+
+/// 服务端配置文件，TOML格式，默认为用户配置目录下的`vpad/config.toml`，也可以通过`--config`指定
+/// 所有配置项都是可选的，CoreMode下命令行参数优先于配置文件
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VPadConfig {
+    // 覆盖log4rs-config.yaml中root的日志级别
+    #[serde(with = "from_str", skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LevelFilter>,
+    // 客户端消息中channel为0时使用的MIDI通道，1~16，默认为1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    // 把客户端的音符对齐到音阶内，比如"C major"，默认为chromatic，不做对齐
+    #[serde(with = "from_str", skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Scale>,
+    // Arp与Chord的速度来源：client或daw，默认为client
+    #[serde(with = "from_str", skip_serializing_if = "Option::is_none")]
+    pub tempo_source: Option<TempoSource>,
+    pub midi: MidiConfig,
+    pub server: ServerConfig,
+    pub daw: DawConfig,
+    pub security: SecurityConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MidiConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instrument_port: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_port: Option<String>,
+    // 默认与控制端口同名，为空字符串时不监听DAW反馈
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feedback_port: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bind: Vec<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backlog: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dual_stack: Option<bool>,
+    // 为0时不开启WebSocket与网页控制器
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ws_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discovery_port: Option<u16>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DawConfig {
+    // 客户端没有指定DAW时使用的DAW配置
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pairing: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    // 新连接的客户端的角色
+    #[serde(with = "from_str", skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(PathBuf, std::io::Error),
+    ParseError(PathBuf, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::IOError(path, e) => write!(f, "cannot access config file {:?}: {}", path, e),
+            ConfigError::ParseError(path, e) => write!(f, "cannot parse config file {:?}: {}", path, e),
+        }
+    }
+}
+
+impl VPadConfig {
+    /// 用overrides中给出的值覆盖当前的值，bind不为空时整体覆盖
+    pub fn merge(self, overrides: VPadConfig) -> VPadConfig {
+        VPadConfig {
+            log_level: overrides.log_level.or(self.log_level),
+            channel: overrides.channel.or(self.channel),
+            scale: overrides.scale.or(self.scale),
+            tempo_source: overrides.tempo_source.or(self.tempo_source),
+            midi: MidiConfig {
+                instrument_port: overrides.midi.instrument_port.or(self.midi.instrument_port),
+                control_port: overrides.midi.control_port.or(self.midi.control_port),
+                feedback_port: overrides.midi.feedback_port.or(self.midi.feedback_port),
+            },
+            server: ServerConfig {
+                bind: if overrides.server.bind.is_empty() { self.server.bind } else { overrides.server.bind },
+                port: overrides.server.port.or(self.server.port),
+                backlog: overrides.server.backlog.or(self.server.backlog),
+                dual_stack: overrides.server.dual_stack.or(self.server.dual_stack),
+                ws_port: overrides.server.ws_port.or(self.server.ws_port),
+                discovery_port: overrides.server.discovery_port.or(self.server.discovery_port),
+            },
+            daw: DawConfig {
+                profile: overrides.daw.profile.or(self.daw.profile),
+                profile_dir: overrides.daw.profile_dir.or(self.daw.profile_dir),
+            },
+            security: SecurityConfig {
+                pairing: overrides.security.pairing.or(self.security.pairing),
+                tls: overrides.security.tls.or(self.security.tls),
+                role: overrides.security.role.or(self.security.role),
+            },
+        }
+    }
+}
+
+/// Linux下是`~/.config/vpad/config.toml`，Windows下是`%APPDATA%\vpad\config.toml`
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("vpad").join("config.toml"))
+}
+
+/// 读取配置文件，文件不存在也是错误，是否使用默认配置由调用者决定
+pub fn load_config(path: &Path) -> Result<VPadConfig, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::IOError(path.to_path_buf(), e))?;
+    toml::from_str(&content).map_err(|e| ConfigError::ParseError(path.to_path_buf(), e.to_string()))
+}
+
+pub fn save_config(path: &Path, config: &VPadConfig) -> Result<(), ConfigError> {
+    let content = toml::to_string_pretty(config).map_err(|e| ConfigError::ParseError(path.to_path_buf(), e.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| ConfigError::IOError(dir.to_path_buf(), e))?;
+    }
+    fs::write(path, content).map_err(|e| ConfigError::IOError(path.to_path_buf(), e))
+}
+
+/// 通过FromStr和Display读写的可选字段，比如角色、日志级别和音阶
+mod from_str {
+    use std::fmt::Display;
+    use std::str::FromStr;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where T: Display, S: Serializer {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+        where T: FromStr, T::Err: Display, D: Deserializer<'de> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use std::env;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use log::LevelFilter;
+    use crate::config::{load_config, save_config, ServerConfig, VPadConfig};
+    use crate::permission::Role;
+    use crate::tempo::TempoSource;
+
+    #[test]
+    fn test_parse_config() {
+        let config: VPadConfig = toml::from_str(r#"
+            log_level = "debug"
+            channel = 2
+            scale = "A minor"
+            tempo_source = "daw"
+
+            [midi]
+            instrument_port = "loopMIDI Port"
+            control_port = "loopMIDI Port 1"
+
+            [server]
+            bind = ["0.0.0.0", "::"]
+            port = 1300
+
+            [daw]
+            profile = "cubase"
+
+            [security]
+            pairing = true
+            role = "mixer"
+        "#).unwrap();
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+        assert_eq!(config.channel, Some(2));
+        assert_eq!(config.scale, Some("A minor".parse().unwrap()));
+        assert_eq!(config.tempo_source, Some(TempoSource::Daw));
+        assert_eq!(config.midi.instrument_port.as_deref(), Some("loopMIDI Port"));
+        assert_eq!(config.midi.feedback_port, None);
+        assert_eq!(config.server.bind.len(), 2);
+        assert_eq!(config.server.port, Some(1300));
+        assert_eq!(config.daw.profile.as_deref(), Some("cubase"));
+        assert_eq!(config.security.role, Some(Role::Mixer));
+        assert_eq!(config.security.tls, None);
+    }
+
+    #[test]
+    fn test_reject_invalid_config() {
+        assert!(toml::from_str::<VPadConfig>("[security]\nrole = \"admin\"").is_err());
+        assert!(toml::from_str::<VPadConfig>("log_level = \"loud\"").is_err());
+        assert!(toml::from_str::<VPadConfig>("scale = \"C blues\"").is_err());
+        assert!(toml::from_str::<VPadConfig>("[server]\nprot = 1300").is_err());
+    }
+
+    #[test]
+    fn test_overrides_take_precedence() {
+        let file = VPadConfig {
+            server: ServerConfig { bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], port: Some(1300), ws_port: Some(1237), ..Default::default() },
+            ..Default::default()
+        };
+        let cli = VPadConfig {
+            server: ServerConfig { port: Some(1400), ..Default::default() },
+            ..Default::default()
+        };
+        let merged = file.merge(cli);
+        assert_eq!(merged.server.port, Some(1400));
+        assert_eq!(merged.server.ws_port, Some(1237));
+        assert_eq!(merged.server.bind, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("vpad_config_{}", std::process::id())).join("config.toml");
+        let mut config = VPadConfig::default();
+        config.midi.instrument_port = Some("Instrument".to_string());
+        config.server.ws_port = Some(0);
+        config.security.role = Some(Role::Notes);
+        config.scale = Some("F# dorian".parse().unwrap());
+        save_config(&path, &config).unwrap();
+        assert_eq!(load_config(&path).unwrap(), config);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
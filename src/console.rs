@@ -0,0 +1,64 @@
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::client_registry::{ClientInfo, GLOBAL_CLIENT_REGISTRY};
+use crate::midi_panic::midi_panic;
+use crate::permission::Role;
+
+/// 服务端控制台，服务启动后从标准输入读取命令，用于管理已连接的客户端
+pub async fn run_console() {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => {}
+            ["clients"] => print_clients(),
+            ["panic"] => midi_panic(),
+            ["kick", id] => match id.parse::<u64>() {
+                Ok(id) if GLOBAL_CLIENT_REGISTRY.lock().unwrap().kick(id) => println!("Client {} is kicked", id),
+                _ => println!("Client {} does not exist", id)
+            },
+            ["role", id, role] => match (id.parse::<u64>(), role.parse::<Role>()) {
+                (Ok(id), Ok(role)) if GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_role(id, role) => println!("Client {} is now {}", id, role),
+                (Err(_), _) | (Ok(_), Ok(_)) => println!("Client {} does not exist", id),
+                (Ok(_), Err(e)) => println!("{}", e)
+            },
+            _ => print_help()
+        }
+    }
+}
+
+fn print_clients() {
+    let clients = GLOBAL_CLIENT_REGISTRY.lock().unwrap().clients();
+    if clients.is_empty() {
+        println!("No client connected");
+    }
+    for client in clients {
+        println!("{}", describe_client(&client));
+    }
+}
+
+fn describe_client(client: &ClientInfo) -> String {
+    format!("[{}] {} {:?} role {}, connected {}s ago, active {}s ago, rtt {}, arps {:?}, chords {:?}",
+            client.id,
+            client.name.as_deref().unwrap_or("<no handshake>"),
+            client.addr,
+            client.role,
+            seconds_since(client.connected_since),
+            seconds_since(client.last_activity),
+            client.rtt.map(|rtt| format!("{}ms", rtt.as_millis())).unwrap_or_else(|| "-".into()),
+            client.active_arps,
+            client.active_chords)
+}
+
+fn seconds_since(time: SystemTime) -> u64 {
+    SystemTime::now().duration_since(time).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn print_help() {
+    println!("Commands:");
+    println!("  clients      list connected clients");
+    println!("  kick <id>    disconnect a client");
+    println!("  role <id> <notes|mixer|full>");
+    println!("               change what a client may do: play notes only, also control the mixer, or everything");
+    println!("  panic        stop all arps and chords, release all notes and reset pitch bend and sustain");
+}
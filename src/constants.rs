@@ -7,6 +7,18 @@ pub const PITCHWHEEL_OP: i8 = 5;
 pub const CC_OP: i8 = 7;
 pub const CONTROL_OP: i8 = 8;
 pub const TRACK_OP: i8 = 9;
+pub const DAW_STATE_OP: i8 = 10;
+pub const TRACK_NAME_OP: i8 = 11;
+pub const VPOT_OP: i8 = 12;
+pub const DAW_SELECT_OP: i8 = 13;
+pub const JOG_OP: i8 = 14;
+pub const NOTICE_OP: i8 = 15;
+pub const PANIC_OP: i8 = 16;
+pub const PING_OP: i8 = 17;
+pub const PONG_OP: i8 = 18;
+pub const UDP_SESSION_OP: i8 = 19;
+pub const PAIR_OP: i8 = 20;
+pub const SHUTDOWN_OP: i8 = 21;
 
 
 pub const SERVER_NAME: &str = "VPadServer";
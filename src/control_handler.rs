@@ -1,39 +1,112 @@
 use std::collections::HashMap;
+use std::sync::Mutex;
+use clap::ValueEnum;
 use lazy_static::lazy_static;
 use crate::message::Message::ControlMessage;
 use crate::message::Message;
+use crate::daw_profile::{action_of, ControlAction, DawProfile};
+use crate::daw_state::on_bank_changed;
+use crate::jog_handler::on_scrub_pressed;
 use crate::midi_connect::GLOBAL_CTL_CONNECTOR;
 use crate::midi_note_to_number::*;
+use crate::notice::Notice;
 
-pub fn handle_control_msg(daw: DawType, message: Message) {
+pub fn handle_control_msg(daw: &str, message: Message) -> Result<(), Notice> {
     if let ControlMessage {operation, state, auto_close} = message {
-        if operation > 14 {
-            log::error!("cannot execute control message, because operation code is out of bounds");
-        } else {
-            let note = get_note_by_type(&daw, operation);
-            if state == OP_STATE_ON {
-                GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, 127, 1);
-                GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, 127, 0);
+        let action = action_of(daw, operation).ok_or(Notice::InvalidOperation(operation))?;
+        if action == ControlAction::Unsupported {
+            return Err(Notice::UnsupportedOperation(operation));
+        }
+        if state == OP_STATE_ON {
+            press(&action);
+            match operation {
+                OP_TRACK_BANK_LEFT => on_bank_changed(-1),
+                OP_TRACK_BANK_RIGHT => on_bank_changed(1),
+                OP_SCRUB => on_scrub_pressed(&action),
+                _ => {}
             }
-            if state == OP_STATE_OFF || auto_close == 1 {
-                GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, 0, 1);
-                GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, 0, 0);
+        }
+        if state == OP_STATE_OFF || auto_close == 1 {
+            release(&action);
+        }
+    }
+    Ok(())
+}
+
+pub fn press(action: &ControlAction) {
+    let mut conn = GLOBAL_CTL_CONNECTOR.lock().unwrap();
+    match action {
+        ControlAction::Note { note, channel } => {
+            conn.midi_note_message_with_channel_number(*note as i8, 127, 1, *channel as i8);
+            conn.midi_note_message_with_channel_number(*note as i8, 127, 0, *channel as i8);
+        }
+        ControlAction::Cc { cc, value, channel, .. } => {
+            conn.cc_message_with_channel_number(*cc as i8, *value as i8, *channel as i8);
+        }
+        ControlAction::SysEx(bytes) => conn.raw_message(bytes),
+        ControlAction::Sequence(messages) => {
+            for bytes in messages {
+                conn.raw_message(bytes);
             }
         }
+        ControlAction::Unsupported => {}
     }
 }
 
-fn get_note_by_type(daw: &DawType, operation: i8) -> i8{
-    let vec = MP_DAW_TO_CTL_NOTE.get(daw).unwrap();
-    vec[operation as usize]
+fn release(action: &ControlAction) {
+    let mut conn = GLOBAL_CTL_CONNECTOR.lock().unwrap();
+    match action {
+        ControlAction::Note { note, channel } => {
+            conn.midi_note_message_with_channel_number(*note as i8, 0, 1, *channel as i8);
+            conn.midi_note_message_with_channel_number(*note as i8, 0, 0, *channel as i8);
+        }
+        ControlAction::Cc { cc, off_value: Some(off_value), channel, .. } => {
+            conn.cc_message_with_channel_number(*cc as i8, *off_value as i8, *channel as i8);
+        }
+        _ => {}
+    }
 }
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug, ValueEnum)]
 pub enum DawType {
     McuDefault,
     FLStudio, StudioOne, Protools, Reaper, AbletonLive, Cubase, AdobeAudition, CakeWalk, Logic
 }
 
+impl DawType {
+    /// 协议中的DAW编号，与枚举的声明顺序一致
+    pub fn from_code(code: i8) -> Option<DawType> {
+        if code < 0 { return None; }
+        DawType::value_variants().get(code as usize).copied()
+    }
+
+    pub fn code(&self) -> i8 {
+        DawType::value_variants().iter().position(|daw| daw == self).unwrap() as i8
+    }
+
+    /// 内置DAW配置的名字，比如`mcu-default`、`cubase`
+    pub fn profile_name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+
+    pub fn from_profile_name(name: &str) -> Option<DawType> {
+        DawType::value_variants().iter().find(|daw| daw.profile_name() == name).copied()
+    }
+}
+
+lazy_static! {
+    // 客户端没有指定DAW时使用的DAW配置，可以通过命令行修改
+    static ref DEFAULT_DAW: Mutex<String> = Mutex::new(DawType::McuDefault.profile_name());
+}
+
+pub fn default_daw() -> String {
+    DEFAULT_DAW.lock().unwrap().clone()
+}
+
+pub fn set_default_daw(daw: String) {
+    *DEFAULT_DAW.lock().unwrap() = daw;
+}
+
 const OP_PLAY: i8 = 0i8;                     // 播放
 const OP_STOP: i8 = 1i8;                     // 停止
 const OP_RECORD: i8 = 2i8;                   // 录制
@@ -49,31 +122,147 @@ const OP_CURSOR_D: i8 = 11i8;
 const OP_CLICK: i8 = 12i8;                   // Toggle Tempo
 const OP_TRACK_BANK_LEFT: i8 = 13i8;         // Left trak
 const OP_TRACK_BANK_RIGHT: i8 = 14i8;
+const OP_SELECT_1: i8 = 15i8;                // 选中当前bank中的第1~8个轨道
+const OP_SELECT_8: i8 = 22i8;
+const OP_F1: i8 = 23i8;                      // F1~F8
+const OP_F8: i8 = 30i8;
+const OP_MARKER: i8 = 31i8;
+const OP_NUDGE: i8 = 32i8;
+const OP_REWIND: i8 = 33i8;
+const OP_FAST_FORWARD: i8 = 34i8;
+pub const OP_SCRUB: i8 = 35i8;
+const OP_CHANNEL_LEFT: i8 = 36i8;            // 轨道左移1个
+const OP_CHANNEL_RIGHT: i8 = 37i8;
+const OP_FLIP: i8 = 38i8;
+const OP_AUTOMATION_READ: i8 = 39i8;         // 自动化模式 Read/Off
+const OP_AUTOMATION_WRITE: i8 = 40i8;
+const OP_AUTOMATION_TRIM: i8 = 41i8;
+const OP_AUTOMATION_TOUCH: i8 = 42i8;
+const OP_AUTOMATION_LATCH: i8 = 43i8;
+const OP_GROUP: i8 = 44i8;
+const OP_SHIFT: i8 = 45i8;                   // 修饰键
+const OP_OPTION: i8 = 46i8;
+const OP_CONTROL: i8 = 47i8;
+const OP_ALT: i8 = 48i8;
+// 以下操作不在音符映射表中
+const OP_JOG_LEFT: i8 = 49i8;                // 飞梭逆时针转动一格
+const OP_JOG_RIGHT: i8 = 50i8;               // 飞梭顺时针转动一格
+
+pub const JOG_CC: u8 = 60;
+
+/// DAW配置文件中使用的操作名，下标是操作码
+pub const OPERATION_NAMES: [&str; 51] = [
+    "play", "stop", "record", "undo", "redo", "loop", "save", "zoom",
+    "cursor_left", "cursor_right", "cursor_up", "cursor_down", "click", "track_bank_left", "track_bank_right",
+    "select_1", "select_2", "select_3", "select_4", "select_5", "select_6", "select_7", "select_8",
+    "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8",
+    "marker", "nudge", "rewind", "fast_forward", "scrub", "channel_left", "channel_right", "flip",
+    "automation_read", "automation_write", "automation_trim", "automation_touch", "automation_latch", "group",
+    "shift", "option", "control", "alt", "jog_left", "jog_right",
+];
 
 const OP_STATE_ON: i8 = 1;
 const OP_STATE_OFF: i8 = 0;
 
+// MCU协议中每个操作对应的按钮音符，下标是操作码
+fn mcu_default_notes() -> Vec<i8> {
+    vec![
+        A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2,
+        // select 1~8
+        C_1, C_SHARP_1, D_1, D_SHARP_1, E_1, F_1, F_SHARP_1, G_1,
+        // F1~F8
+        F_SHARP_3, G_3, G_SHARP_3, A_3, A_SHARP_3, B_3, C_4, C_SHARP_4,
+        // marker, nudge, rewind, fast forward, scrub
+        C_6, C_SHARP_6, G_6, G_SHARP_6, F_7,
+        // channel left, channel right, flip
+        C_3, C_SHARP_3, D_3,
+        // read, write, trim, touch, latch, group
+        D_5, D_SHARP_5, E_5, F_5, F_SHARP_5, G_5,
+        // shift, option, control, alt
+        A_SHARP_4, B_4, C_5, C_SHARP_5,
+    ]
+}
+
+// 在MCU默认映射的基础上，覆盖某个DAW中与默认不同的操作
+fn with_overrides(overrides: &[(i8, i8)]) -> Vec<i8> {
+    let mut notes = mcu_default_notes();
+    for (operation, note) in overrides {
+        notes[*operation as usize] = *note;
+    }
+    notes
+}
+
 fn init_map() -> HashMap<DawType, Vec<i8>> {
     let mut map: HashMap<DawType, Vec<i8>> = HashMap::new();
-    map.insert(DawType::McuDefault,     vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::FLStudio,       vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::StudioOne,      vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::Protools,       vec![A_SHARP_6, A_6, B_6, A_5, G_5, D_6, G_SHARP_5, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::Reaper,         vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::Cubase,         vec![A_SHARP_6, A_6, B_6, A_SHARP_4, B_4, -1, C_5, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::AdobeAudition,  vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::CakeWalk,       vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::Logic,          vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
-    map.insert(DawType::AbletonLive,    vec![A_SHARP_6, A_6, B_6, E_5, G_5, D_6, -1, D_SHARP_7, C_SHARP_7, E_7, C_7, F_7, -1, A_SHARP_2, B_2]);
+    map.insert(DawType::McuDefault,     mcu_default_notes());
+    map.insert(DawType::FLStudio,       mcu_default_notes());
+    map.insert(DawType::StudioOne,      mcu_default_notes());
+    map.insert(DawType::Protools,       with_overrides(&[(OP_UNDO, A_5), (OP_SAVE, G_SHARP_5)]));
+    map.insert(DawType::Reaper,         mcu_default_notes());
+    map.insert(DawType::Cubase,         with_overrides(&[(OP_UNDO, A_SHARP_4), (OP_REDO, B_4), (OP_LOOP, -1), (OP_SAVE, C_5)]));
+    map.insert(DawType::AdobeAudition,  mcu_default_notes());
+    map.insert(DawType::CakeWalk,       mcu_default_notes());
+    map.insert(DawType::Logic,          mcu_default_notes());
+    map.insert(DawType::AbletonLive,    mcu_default_notes());
     map
 }
 
-lazy_static! {
-    // 从DawType到控制note的映射，若通过查询得到的note小于0，证明在该Daw中不支持此功能，目前知道的不支持列表
-    //  AbletonLive   ==   Save、Click
-    //  Cubase        ==   Loop、Click
-    static ref MP_DAW_TO_CTL_NOTE: HashMap<DawType, Vec<i8>> = {
-        init_map()
-    };
+/// 内置的DAW配置，由DawType到控制note的映射生成，若note小于0，证明在该Daw中不支持此功能，目前知道的不支持列表
+///  AbletonLive   ==   Save、Click
+///  Cubase        ==   Loop、Click
+/// 飞梭不是按钮，而是相对值的CC 60，每次转动一格
+pub fn builtin_profiles() -> Vec<DawProfile> {
+    init_map().into_iter().map(|(daw, notes)| {
+        let mut actions: Vec<ControlAction> = notes.into_iter().map(|note| {
+            if note < 0 { ControlAction::Unsupported } else { ControlAction::Note { note: note as u8, channel: 1 } }
+        }).collect();
+        actions.push(ControlAction::Cc { cc: JOG_CC, value: 0x41, off_value: None, channel: 1 });
+        actions.push(ControlAction::Cc { cc: JOG_CC, value: 0x01, off_value: None, channel: 1 });
+        DawProfile { name: daw.profile_name(), actions }
+    }).collect()
 }
 
+
+#[cfg(test)]
+mod control_handler_test {
+    use crate::control_handler::*;
+
+    fn action(daw: DawType, operation: i8) -> ControlAction {
+        builtin_profiles().into_iter().find(|profile| profile.name == daw.profile_name()).unwrap()
+            .action(operation).unwrap().clone()
+    }
+
+    #[test]
+    fn test_unsupported_operation_is_noticed() {
+        let message = |operation| ControlMessage { operation, state: OP_STATE_ON, auto_close: 1 };
+        let cubase = DawType::Cubase.profile_name();
+        assert_eq!(handle_control_msg(&cubase, message(OP_LOOP)), Err(Notice::UnsupportedOperation(OP_LOOP)));
+        assert_eq!(handle_control_msg(&cubase, message(100)), Err(Notice::InvalidOperation(100)));
+    }
+
+    #[test]
+    fn test_daw_code() {
+        assert_eq!(DawType::from_code(0), Some(DawType::McuDefault));
+        assert_eq!(DawType::from_code(6), Some(DawType::Cubase));
+        assert_eq!(DawType::from_code(10), None);
+        assert_eq!(DawType::from_code(-1), None);
+        assert_eq!(DawType::Logic.code(), 9);
+    }
+
+    #[test]
+    fn test_profile_name() {
+        assert_eq!(DawType::McuDefault.profile_name(), "mcu-default");
+        assert_eq!(DawType::from_profile_name("cubase"), Some(DawType::Cubase));
+        assert_eq!(DawType::from_profile_name("foo"), None);
+    }
+
+    #[test]
+    fn test_overrides() {
+        assert_eq!(action(DawType::McuDefault, OP_UNDO), ControlAction::Note { note: E_5 as u8, channel: 1 });
+        assert_eq!(action(DawType::Cubase, OP_UNDO), ControlAction::Note { note: A_SHARP_4 as u8, channel: 1 });
+        assert_eq!(action(DawType::Cubase, OP_LOOP), ControlAction::Unsupported);
+        assert_eq!(action(DawType::Cubase, OP_SELECT_1), ControlAction::Note { note: C_1 as u8, channel: 1 });
+        assert_eq!(action(DawType::Protools, OP_SELECT_8), ControlAction::Note { note: G_1 as u8, channel: 1 });
+        assert_eq!(action(DawType::Logic, OP_JOG_LEFT), ControlAction::Cc { cc: 60, value: 0x41, off_value: None, channel: 1 });
+    }
+}
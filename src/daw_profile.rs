@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use crate::control_handler::{builtin_profiles, OPERATION_NAMES};
+
+/// 一个控制操作在DAW中实际要发送的MIDI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAction {
+    // 按钮，按下时发送力度127，松开时发送力度0
+    Note { note: u8, channel: u8 },
+    // 按下时发送value，松开时如果有off_value就发送off_value
+    Cc { cc: u8, value: u8, off_value: Option<u8>, channel: u8 },
+    // 按下时发送一条SysEx
+    SysEx(Vec<u8>),
+    // 按下时依次发送多条原始MIDI消息
+    Sequence(Vec<Vec<u8>>),
+    // 该DAW不支持这个操作
+    Unsupported
+}
+
+/// DAW配置，定义每个控制操作如何转换为MIDI，actions的下标是操作码
+#[derive(Debug, Clone)]
+pub struct DawProfile {
+    pub name: String,
+    pub actions: Vec<ControlAction>
+}
+
+impl DawProfile {
+    pub fn action(&self, operation: i8) -> Option<&ControlAction> {
+        if operation < 0 { return None; }
+        self.actions.get(operation as usize)
+    }
+}
+
+lazy_static! {
+    // 所有可用的DAW配置，内置配置在启动时被用户配置目录中的同名配置覆盖
+    static ref GLOBAL_DAW_PROFILES: RwLock<HashMap<String, DawProfile>> = RwLock::new(
+        builtin_profiles().into_iter().map(|profile| (profile.name.clone(), profile)).collect()
+    );
+}
+
+pub fn profile_exists(name: &str) -> bool {
+    GLOBAL_DAW_PROFILES.read().unwrap().contains_key(name)
+}
+
+pub fn profile_names() -> Vec<String> {
+    let mut names: Vec<String> = GLOBAL_DAW_PROFILES.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
+
+/// 查询某个DAW配置中操作对应的动作，配置不存在或操作码越界时返回None
+pub fn action_of(profile: &str, operation: i8) -> Option<ControlAction> {
+    GLOBAL_DAW_PROFILES.read().unwrap().get(profile)?.action(operation).cloned()
+}
+
+/// 用户配置目录，Windows下是`%APPDATA%\vpad\profiles`，Linux下是`~/.config/vpad/profiles`
+pub fn default_profile_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("vpad").join("profiles"))
+}
+
+/// 加载目录中所有的`.toml`和`.json`配置，任何一个配置不合法都会返回错误
+/// 目录不存在时什么也不做
+pub fn load_profile_dir(dir: &Path) -> Result<Vec<String>, DawProfileError> {
+    if !dir.is_dir() { return Ok(Vec::new()); }
+    let entries = fs::read_dir(dir).map_err(|e| DawProfileError::IOError(dir.to_path_buf(), e))?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| matches!(path.extension().and_then(|ext| ext.to_str()), Some("toml") | Some("json")))
+        .collect();
+    paths.sort();
+
+    let mut loaded = Vec::with_capacity(paths.len());
+    for path in paths {
+        let profile = load_profile_file(&path)?;
+        log::info!("Loaded daw profile [{}] from {:?}", profile.name, path);
+        loaded.push(profile.name.clone());
+        GLOBAL_DAW_PROFILES.write().unwrap().insert(profile.name.clone(), profile);
+    }
+    Ok(loaded)
+}
+
+fn load_profile_file(path: &Path) -> Result<DawProfile, DawProfileError> {
+    let content = fs::read_to_string(path).map_err(|e| DawProfileError::IOError(path.to_path_buf(), e))?;
+    let is_json = path.extension().and_then(|ext| ext.to_str()) == Some("json");
+    let file: ProfileFile = if is_json {
+        serde_json::from_str(&content).map_err(|e| DawProfileError::ParseError(path.to_path_buf(), e.to_string()))?
+    } else {
+        toml::from_str(&content).map_err(|e| DawProfileError::ParseError(path.to_path_buf(), e.to_string()))?
+    };
+    let default_name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+    build_profile(file, default_name).map_err(|reason| DawProfileError::InvalidProfile(path.to_path_buf(), reason))
+}
+
+// ------ 配置文件格式 ------ //
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    // 配置名，默认为文件名
+    name: Option<String>,
+    // 未列出的操作继承自哪个内置配置，默认为mcu-default
+    base: Option<String>,
+    #[serde(default)]
+    operations: HashMap<String, ActionFile>
+}
+
+// 数值都先按i64读取，这样越界时可以给出更明确的错误
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActionFile {
+    note: Option<i64>,
+    cc: Option<i64>,
+    value: Option<i64>,
+    off_value: Option<i64>,
+    sysex: Option<Vec<i64>>,
+    sequence: Option<Vec<Vec<i64>>>,
+    channel: Option<i64>,
+    #[serde(default)]
+    unsupported: bool
+}
+
+fn build_profile(file: ProfileFile, default_name: String) -> Result<DawProfile, String> {
+    let name = file.name.unwrap_or(default_name);
+    if name.is_empty() {
+        return Err("profile name cannot be empty".to_string());
+    }
+    let base_name = file.base.unwrap_or_else(|| BASE_PROFILE.to_string());
+    let mut actions = builtin_profiles().into_iter()
+        .find(|profile| profile.name == base_name)
+        .ok_or_else(|| format!("base profile '{}' is not a builtin profile", base_name))?
+        .actions;
+
+    for (operation_name, action) in file.operations {
+        let operation = OPERATION_NAMES.iter().position(|name| *name == operation_name)
+            .ok_or_else(|| format!("unknown operation '{}', available operations are: {}", operation_name, OPERATION_NAMES.join(", ")))?;
+        actions[operation] = build_action(action).map_err(|reason| format!("operation '{}': {}", operation_name, reason))?;
+    }
+    Ok(DawProfile { name, actions })
+}
+
+fn build_action(action: ActionFile) -> Result<ControlAction, String> {
+    let kinds = [action.note.is_some(), action.cc.is_some(), action.sysex.is_some(), action.sequence.is_some(), action.unsupported];
+    if kinds.iter().filter(|kind| **kind).count() != 1 {
+        return Err("exactly one of `note`, `cc`, `sysex`, `sequence` or `unsupported` must be given".to_string());
+    }
+    let channel = to_data_byte("channel", action.channel.unwrap_or(1), 1, 16)?;
+
+    if let Some(note) = action.note {
+        return Ok(ControlAction::Note { note: to_data_byte("note", note, 0, 127)?, channel });
+    }
+    if let Some(cc) = action.cc {
+        return Ok(ControlAction::Cc {
+            cc: to_data_byte("cc", cc, 0, 127)?,
+            value: to_data_byte("value", action.value.unwrap_or(127), 0, 127)?,
+            off_value: action.off_value.map(|off_value| to_data_byte("off_value", off_value, 0, 127)).transpose()?,
+            channel
+        });
+    }
+    if let Some(sysex) = action.sysex {
+        return Ok(ControlAction::SysEx(to_midi_message(&sysex)?));
+    }
+    if let Some(sequence) = action.sequence {
+        if sequence.is_empty() {
+            return Err("`sequence` cannot be empty".to_string());
+        }
+        let messages: Result<Vec<Vec<u8>>, String> = sequence.iter().map(|message| to_midi_message(message)).collect();
+        return Ok(ControlAction::Sequence(messages?));
+    }
+    Ok(ControlAction::Unsupported)
+}
+
+fn to_data_byte(field: &str, value: i64, min: i64, max: i64) -> Result<u8, String> {
+    if value < min || value > max {
+        return Err(format!("`{}` is {}, but it must be in {}~{}", field, value, min, max));
+    }
+    Ok(value as u8)
+}
+
+/// 校验一条原始MIDI消息：第一个字节是状态字节，其后都是数据字节，SysEx必须以F7结尾
+fn to_midi_message(bytes: &[i64]) -> Result<Vec<u8>, String> {
+    if bytes.is_empty() {
+        return Err("midi message cannot be empty".to_string());
+    }
+    if !(0x80..=0xFF).contains(&bytes[0]) {
+        return Err(format!("midi message must start with a status byte, but got {:#X}", bytes[0]));
+    }
+    let is_sysex = bytes[0] == 0xF0;
+    let data = if is_sysex {
+        if bytes.len() < 2 || bytes[bytes.len() - 1] != 0xF7 {
+            return Err("sysex message must end with 0xF7".to_string());
+        }
+        &bytes[1..bytes.len() - 1]
+    } else {
+        &bytes[1..]
+    };
+    for byte in data {
+        if !(0..=0x7F).contains(byte) {
+            return Err(format!("midi data byte must be in 0x00~0x7F, but got {:#X}", byte));
+        }
+    }
+    Ok(bytes.iter().map(|byte| *byte as u8).collect())
+}
+
+const BASE_PROFILE: &str = "mcu-default";
+
+// ------ 错误封装 ------ //
+#[derive(Debug)]
+pub enum DawProfileError {
+    IOError(PathBuf, std::io::Error),
+    ParseError(PathBuf, String),
+    InvalidProfile(PathBuf, String)
+}
+impl Display for DawProfileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DawProfileError::IOError(path, e) => write!(f, "cannot read daw profile {:?}: {}", path, e),
+            DawProfileError::ParseError(path, e) => write!(f, "cannot parse daw profile {:?}: {}", path, e),
+            DawProfileError::InvalidProfile(path, e) => write!(f, "invalid daw profile {:?}: {}", path, e),
+        }
+    }
+}
+// ------ 错误封装 ------ //
+
+#[cfg(test)]
+mod daw_profile_test {
+    use crate::control_handler::{builtin_profiles, OPERATION_NAMES};
+    use crate::daw_profile::{build_profile, ControlAction, ProfileFile};
+
+    fn parse_toml(content: &str) -> Result<ControlAction, String> {
+        let file: ProfileFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        let profile = build_profile(file, "test".to_string())?;
+        Ok(profile.actions[3].clone())
+    }
+
+    #[test]
+    fn test_builtin_profiles_cover_all_operations() {
+        for profile in builtin_profiles() {
+            assert_eq!(profile.actions.len(), OPERATION_NAMES.len());
+        }
+        // 操作名表要一直覆盖到最后一个操作码
+        assert_eq!(OPERATION_NAMES.last(), Some(&"jog_right"));
+    }
+
+    #[test]
+    fn test_parse_toml_profile() {
+        let file: ProfileFile = toml::from_str(r#"
+            name = "my-cubase"
+            base = "cubase"
+            [operations]
+            save = { cc = 80, value = 100, channel = 2 }
+            f1 = { sysex = [0xF0, 0x00, 0x01, 0xF7] }
+            f2 = { sequence = [[0x90, 0x36, 0x7F], [0x80, 0x36, 0x00]] }
+            marker = { unsupported = true }
+        "#).unwrap();
+        let profile = build_profile(file, "ignored".to_string()).unwrap();
+        assert_eq!(profile.name, "my-cubase");
+        // 继承自cubase
+        assert_eq!(profile.action(3), Some(&ControlAction::Note { note: 70, channel: 1 }));
+        assert_eq!(profile.action(5), Some(&ControlAction::Unsupported));
+        assert_eq!(profile.action(6), Some(&ControlAction::Cc { cc: 80, value: 100, off_value: None, channel: 2 }));
+        assert_eq!(profile.action(23), Some(&ControlAction::SysEx(vec![0xF0, 0x00, 0x01, 0xF7])));
+        assert_eq!(profile.action(24), Some(&ControlAction::Sequence(vec![vec![0x90, 0x36, 0x7F], vec![0x80, 0x36, 0x00]])));
+        assert_eq!(profile.action(31), Some(&ControlAction::Unsupported));
+        assert_eq!(profile.action(-1), None);
+    }
+
+    #[test]
+    fn test_parse_json_profile() {
+        let file: ProfileFile = serde_json::from_str(r#"{"operations": {"undo": {"note": 81}}}"#).unwrap();
+        let profile = build_profile(file, "from-file-name".to_string()).unwrap();
+        assert_eq!(profile.name, "from-file-name");
+        assert_eq!(profile.action(3), Some(&ControlAction::Note { note: 81, channel: 1 }));
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        assert!(parse_toml("[operations]\nundo = { note = 128 }").unwrap_err().contains("`note` is 128"));
+        assert!(parse_toml("[operations]\nundo = { note = 1, channel = 17 }").unwrap_err().contains("`channel` is 17"));
+        assert!(parse_toml("[operations]\nundo = { note = 1, cc = 1 }").unwrap_err().contains("exactly one of"));
+        assert!(parse_toml("[operations]\nundo = { sysex = [0xF0, 0x01] }").unwrap_err().contains("0xF7"));
+        assert!(parse_toml("[operations]\nundo = { sequence = [[0x01, 0x02]] }").unwrap_err().contains("status byte"));
+        assert!(parse_toml("[operations]\nundo = { sequence = [[0x90, 0x80, 0x00]] }").unwrap_err().contains("data byte"));
+        assert!(parse_toml("[operations]\nfoo = { note = 1 }").unwrap_err().contains("unknown operation 'foo'"));
+        assert!(parse_toml("base = \"foo\"").unwrap_err().contains("base profile 'foo'"));
+        assert!(parse_toml("[operations]\nundo = { note = 1, velocity = 1 }").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::mcu_feedback::*;
+use crate::message::Message;
+use crate::message::Message::{TrackMessage, TrackNameMessage};
+use crate::server::broadcast_to_clients;
+use crate::tempo::{MIDI_CLOCK_STATUS, on_midi_clock};
+use crate::track_handler::STATE_FADER_VALUE_CHANGED;
+
+/// DawState是服务端对DAW当前状态的镜像，它全局唯一
+/// 由控制端口的MIDI输入驱动，其它子系统和客户端可以通过它查询DAW的状态
+pub struct DawState {
+    lcd: [u8; LCD_SIZE],
+    // 下标0是最右边的一位
+    timecode: [(char, bool); TIMECODE_DIGITS],
+    assignment: [(char, bool); ASSIGNMENT_DIGITS],
+    vpot_rings: [u8; 8],
+    meters: [u8; 8],
+    meter_overloads: [bool; 8],
+    button_leds: HashMap<u8, LedState>,
+    // 0~7是通道推子，8是Master推子
+    faders: [u16; 9],
+    // 客户端是否正按着推子，按着时DAW回传的推子位置不会推送给客户端
+    fader_touched: [bool; 9],
+    // 当前的bank，每个bank包含8个轨道，OP_TRACK_BANK_LEFT/RIGHT会改变它
+    bank: i8,
+    // 每个bank中8个轨道的名字，来自LCD第一行的scribble strip
+    bank_track_names: HashMap<i8, Vec<String>>,
+}
+
+impl DawState {
+    pub fn new() -> DawState {
+        DawState {
+            lcd: [b' '; LCD_SIZE],
+            timecode: [(' ', false); TIMECODE_DIGITS],
+            assignment: [(' ', false); ASSIGNMENT_DIGITS],
+            vpot_rings: [0; 8],
+            meters: [0; 8],
+            meter_overloads: [false; 8],
+            button_leds: HashMap::new(),
+            faders: [0; 9],
+            fader_touched: [false; 9],
+            bank: 0,
+            bank_track_names: HashMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, feedback: McuFeedback) {
+        match feedback {
+            McuFeedback::LcdText { offset, text } => {
+                for (i, c) in text.into_iter().enumerate() {
+                    let pos = offset as usize + i;
+                    if pos >= LCD_SIZE { break; }
+                    self.lcd[pos] = c;
+                }
+            }
+            McuFeedback::TimecodeDigit { digit, character, dot } => {
+                self.timecode[digit as usize] = (character, dot);
+            }
+            McuFeedback::AssignmentDigit { digit, character, dot } => {
+                self.assignment[digit as usize] = (character, dot);
+            }
+            McuFeedback::VPotRing { nth, value } => self.vpot_rings[nth as usize] = value,
+            McuFeedback::Meter { nth, level } => self.meters[nth as usize] = level,
+            McuFeedback::MeterOverload { nth, overload } => self.meter_overloads[nth as usize] = overload,
+            McuFeedback::ButtonLed { note, state } => {
+                self.button_leds.insert(note, state);
+            }
+            McuFeedback::Fader { nth, value } => {
+                if let Some(fader) = self.faders.get_mut(nth as usize) {
+                    *fader = value;
+                }
+            }
+        }
+    }
+
+    /// LCD的第row行（0或1）
+    pub fn lcd_line(&self, row: usize) -> String {
+        let start = row * LCD_WIDTH;
+        String::from_utf8_lossy(&self.lcd[start..start + LCD_WIDTH]).into_owned()
+    }
+
+    /// 时间码显示，从左到右，带小数点
+    pub fn timecode_text(&self) -> String {
+        display_text(&self.timecode)
+    }
+
+    pub fn assignment_text(&self) -> String {
+        display_text(&self.assignment)
+    }
+
+    pub fn vpot_ring(&self, nth: usize) -> u8 {
+        self.vpot_rings[nth]
+    }
+
+    pub fn meter(&self, nth: usize) -> (u8, bool) {
+        (self.meters[nth], self.meter_overloads[nth])
+    }
+
+    pub fn button_led(&self, note: u8) -> LedState {
+        *self.button_leds.get(&note).unwrap_or(&LedState::Off)
+    }
+
+    pub fn fader(&self, nth: usize) -> u16 {
+        self.faders[nth]
+    }
+
+    pub fn set_fader_touched(&mut self, nth: usize, touched: bool) {
+        if let Some(fader_touched) = self.fader_touched.get_mut(nth) {
+            *fader_touched = touched;
+        }
+    }
+
+    pub fn is_fader_touched(&self, nth: usize) -> bool {
+        self.fader_touched.get(nth).copied().unwrap_or(false)
+    }
+
+    /// 推子位置对应的TrackMessage，nth从0开始
+    pub fn fader_message(&self, nth: usize) -> Message {
+        TrackMessage {
+            nth: nth as i8 + 1,
+            state: STATE_FADER_VALUE_CHANGED,
+            value: (self.faders[nth] >> 7) as i8,
+            fine_value: Some(self.faders[nth] as i16)
+        }
+    }
+
+    pub fn bank(&self) -> i8 {
+        self.bank
+    }
+
+    pub fn shift_bank(&mut self, delta: i8) {
+        self.bank = max(0, self.bank.saturating_add(delta));
+    }
+
+    /// LCD第一行被分为8个scribble strip，每个7个字符，分别显示8个轨道的名字
+    pub fn scribble_strips(&self) -> Vec<String> {
+        self.lcd[..LCD_WIDTH].chunks(STRIP_WIDTH)
+            .map(|strip| String::from_utf8_lossy(strip).trim().to_string())
+            .collect()
+    }
+
+    /// 用LCD中的名字更新当前bank的缓存，如果名字发生了变化返回true
+    pub fn refresh_track_names(&mut self) -> bool {
+        let names = self.scribble_strips();
+        if self.bank_track_names.get(&self.bank) == Some(&names) {
+            return false;
+        }
+        self.bank_track_names.insert(self.bank, names);
+        true
+    }
+
+    /// 当前bank的轨道名，还没有收到过该bank的LCD更新时，名字都是空的
+    pub fn track_name_message(&self) -> Message {
+        TrackNameMessage {
+            bank: self.bank,
+            names: self.bank_track_names.get(&self.bank).cloned().unwrap_or_else(|| vec![String::new(); 8])
+        }
+    }
+
+    /// 时间码显示当前是否处于BBT模式，DAW通过BEATS按钮灯告知
+    pub fn is_bbt_mode(&self) -> bool {
+        self.button_led(NOTE_BEATS_LED) == LedState::On
+    }
+}
+
+fn display_text(digits: &[(char, bool)]) -> String {
+    let mut text = String::new();
+    for (character, dot) in digits.iter().rev() {
+        text.push(*character);
+        if *dot { text.push('.'); }
+    }
+    text
+}
+
+/// MIDI输入回调，解析DAW的反馈并更新全局状态
+pub fn on_control_feedback(bytes: &[u8]) {
+    // DAW也可以把MIDI时钟发到反馈端口，用作Arp与Chord的速度
+    if bytes == [MIDI_CLOCK_STATUS] {
+        on_midi_clock();
+        return;
+    }
+    if let Some(feedback) = parse_mcu_message(bytes) {
+        log::debug!("Got an mcu feedback => {:?}", feedback);
+        // 只有写到了LCD第一行的更新才可能改变轨道名
+        let touches_strips = matches!(feedback, McuFeedback::LcdText { offset, .. } if (offset as usize) < LCD_WIDTH);
+        let mut daw_state = GLOBAL_DAW_STATE.lock().unwrap();
+        if let McuFeedback::Fader { nth, value } = feedback {
+            on_fader_feedback(&mut daw_state, nth as usize, value);
+            return;
+        }
+        daw_state.apply(feedback);
+        if touches_strips && daw_state.refresh_track_names() {
+            broadcast_to_clients(daw_state.track_name_message());
+        }
+    }
+}
+
+/// DAW移动了推子（比如自动化），推送给客户端，让客户端的推子跟着动
+/// 客户端按着推子时不推送，避免和用户的操作打架
+fn on_fader_feedback(daw_state: &mut DawState, nth: usize, value: u16) {
+    if nth >= daw_state.faders.len() { return; }
+    let changed = daw_state.faders[nth] != value;
+    daw_state.faders[nth] = value;
+    if changed && !daw_state.is_fader_touched(nth) {
+        broadcast_to_clients(daw_state.fader_message(nth));
+    }
+}
+
+/// 客户端按下或松开了推子，松开时把DAW当前的推子位置同步给客户端
+pub fn on_fader_touched(nth: usize, touched: bool) {
+    let mut daw_state = GLOBAL_DAW_STATE.lock().unwrap();
+    daw_state.set_fader_touched(nth, touched);
+    if !touched && nth < daw_state.faders.len() {
+        broadcast_to_clients(daw_state.fader_message(nth));
+    }
+}
+
+/// 客户端切换了bank，把已知的轨道名先推给客户端，DAW随后刷新LCD时会再推送一次
+pub fn on_bank_changed(delta: i8) {
+    let mut daw_state = GLOBAL_DAW_STATE.lock().unwrap();
+    daw_state.shift_bank(delta);
+    broadcast_to_clients(daw_state.track_name_message());
+}
+
+const NOTE_BEATS_LED: u8 = 0x72;
+const STRIP_WIDTH: usize = LCD_WIDTH / 8;
+
+lazy_static! {
+    pub static ref GLOBAL_DAW_STATE: Mutex<DawState> = Mutex::new(DawState::new());
+}
+
+#[cfg(test)]
+mod daw_state_test {
+    use crate::daw_state::DawState;
+    use crate::mcu_feedback::{LedState, McuFeedback};
+    use crate::message::Message::{TrackMessage, TrackNameMessage};
+    use crate::track_handler::STATE_FADER_VALUE_CHANGED;
+
+    #[test]
+    fn test_lcd_text() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::LcdText { offset: 0, text: b"Kick".to_vec() });
+        state.apply(McuFeedback::LcdText { offset: 56, text: b"-3.0".to_vec() });
+        assert!(state.lcd_line(0).starts_with("Kick   "));
+        assert!(state.lcd_line(1).starts_with("-3.0   "));
+        assert_eq!(state.lcd_line(0).len(), 56);
+    }
+
+    #[test]
+    fn test_lcd_text_overflow_is_truncated() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::LcdText { offset: 110, text: b"abcd".to_vec() });
+        assert!(state.lcd_line(1).ends_with("ab"));
+    }
+
+    #[test]
+    fn test_timecode_text() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::TimecodeDigit { digit: 0, character: '1', dot: false });
+        state.apply(McuFeedback::TimecodeDigit { digit: 1, character: '2', dot: true });
+        assert_eq!(state.timecode_text(), "        2.1");
+    }
+
+    #[test]
+    fn test_track_names_are_kept_per_bank() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::LcdText { offset: 0, text: b"Kick   Snare  ".to_vec() });
+        assert!(state.refresh_track_names());
+        assert!(!state.refresh_track_names());
+
+        state.shift_bank(1);
+        state.apply(McuFeedback::LcdText { offset: 0, text: b"Bass   Keys   ".to_vec() });
+        assert!(state.refresh_track_names());
+
+        state.shift_bank(-1);
+        if let TrackNameMessage { bank, names } = state.track_name_message() {
+            assert_eq!(bank, 0);
+            assert_eq!(names[0], "Kick");
+            assert_eq!(names[1], "Snare");
+            assert_eq!(names[2], "");
+        } else {
+            panic!("not a track name message");
+        }
+    }
+
+    #[test]
+    fn test_fader_touch() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::Fader { nth: 2, value: 0x3FFF });
+        state.set_fader_touched(2, true);
+        assert!(state.is_fader_touched(2));
+        assert!(!state.is_fader_touched(3));
+        assert!(!state.is_fader_touched(100));
+        if let TrackMessage { nth, state, value, fine_value } = state.fader_message(2) {
+            assert_eq!(nth, 3);
+            assert_eq!(state, STATE_FADER_VALUE_CHANGED);
+            assert_eq!(value, 127);
+            assert_eq!(fine_value, Some(0x3FFF));
+        } else {
+            panic!("not a track message");
+        }
+    }
+
+    #[test]
+    fn test_bank_cannot_be_negative() {
+        let mut state = DawState::new();
+        state.shift_bank(-1);
+        assert_eq!(state.bank(), 0);
+    }
+
+    #[test]
+    fn test_button_led_and_fader() {
+        let mut state = DawState::new();
+        state.apply(McuFeedback::ButtonLed { note: 0x72, state: LedState::On });
+        state.apply(McuFeedback::Fader { nth: 8, value: 0x1234 });
+        assert!(state.is_bbt_mode());
+        assert_eq!(state.button_led(0x5E), LedState::Off);
+        assert_eq!(state.fader(8), 0x1234);
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig, V4IfAddr};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use crate::constants::{SERVER_NAME, SERVER_PLATFORM, SERVER_VERSION};
+use crate::pairing::pairing_pin;
+use crate::tls::tls_fingerprint;
+use crate::udp_server::udp_session_port;
+
+/// 广播发现，作为mDNS被组播屏蔽时的备选方案
+/// 客户端向DISCOVERY_PORT广播DISCOVERY_PROBE，服务端回复一个JSON，包含连接所需的信息
+pub const DISCOVERY_PORT: u16 = 1238;
+pub const DISCOVERY_PROBE: &[u8] = b"VPAD_DISCOVER";
+
+#[derive(Serialize)]
+struct DiscoveryReply {
+    name: &'static str,
+    version: &'static str,
+    platform: &'static str,
+    // 收到探测包的网络接口的ip，客户端应当连接这个地址
+    ip: String,
+    port: u16,
+    features: Vec<&'static str>,
+}
+
+pub async fn serve_discovery(port: u16, server_port: u16, ws_port: Option<u16>) {
+    let socket = match UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Cannot bind discovery port {}, discovery is disabled: {:?}", port, e);
+            return;
+        }
+    };
+    let mut buf = [0u8; MAX_PROBE_SIZE];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::info!("Read from discovery port error: {:?}", e);
+                continue;
+            }
+        };
+        // 每次都重新获取网络接口，电脑切换网络后不需要重启服务端
+        let reply = reply_to_probe(&buf[..len], src, &vaild_ipv4_interfaces(), server_port, ws_port);
+        if let Some(reply) = reply {
+            log::debug!("Reply discovery probe from {:?}", src);
+            if let Err(e) = socket.send_to(&reply, src).await {
+                log::info!("Cannot reply discovery probe to {:?}: {:?}", src, e);
+            }
+        }
+    }
+}
+
+/// 只回复来自合法网络接口所在子网的探测包，回复中的ip就是这个接口的ip
+fn reply_to_probe(probe: &[u8], src: SocketAddr, interfaces: &[V4IfAddr], server_port: u16, ws_port: Option<u16>) -> Option<Vec<u8>> {
+    if String::from_utf8_lossy(probe).trim().as_bytes() != DISCOVERY_PROBE { return None; }
+    let src = match src.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return None
+    };
+    let iface = interfaces.iter().find(|iface| in_same_subnet(iface, src))?;
+    let reply = DiscoveryReply {
+        name: SERVER_NAME,
+        version: SERVER_VERSION,
+        platform: SERVER_PLATFORM,
+        ip: iface.ip.to_string(),
+        port: server_port,
+        features: features(ws_port),
+    };
+    serde_json::to_vec(&reply).ok()
+}
+
+fn features(ws_port: Option<u16>) -> Vec<&'static str> {
+    let mut features = vec!["tcp"];
+    if udp_session_port() != 0 { features.push("udp"); }
+    if ws_port.is_some() { features.extend(["websocket", "web_pad"]); }
+    if pairing_pin().is_some() { features.push("pairing"); }
+    if tls_fingerprint().is_some() { features.push("tls"); }
+    features
+}
+
+fn in_same_subnet(iface: &V4IfAddr, ip: Ipv4Addr) -> bool {
+    match iface.netmask {
+        Some(mask) => u32::from(iface.ip) & u32::from(mask) == u32::from(ip) & u32::from(mask),
+        None => iface.ip == ip
+    }
+}
+
+/// 遍历每个网络接口，获取所有合法的ipv4地址
+///     1. 必须是ipv4
+///     2. 必须不能是loopback
+///     3. 如果一个接口上有多个ip，取第一个符合的ip
+/// 获取网络接口失败时返回空列表，探测包不会被回复
+pub fn vaild_ipv4_interfaces() -> Vec<V4IfAddr> {
+    let interfaces = match NetworkInterface::show() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            log::warn!("Cannot get network interfaces: {:?}", e);
+            return Vec::new();
+        }
+    };
+    interfaces.iter().filter_map(|iface| {
+        iface.addr.iter().find_map(|addr| match addr {
+            Addr::V4(addr) if !addr.ip.is_loopback() => Some(*addr),
+            _ => None
+        })
+    }).collect()
+}
+
+const MAX_PROBE_SIZE: usize = 64;
+
+#[cfg(test)]
+mod discovery_test {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use network_interface::V4IfAddr;
+    use crate::discovery::{DISCOVERY_PROBE, reply_to_probe};
+
+    fn lan() -> Vec<V4IfAddr> {
+        vec![V4IfAddr {
+            ip: Ipv4Addr::new(192, 168, 1, 10),
+            broadcast: Some(Ipv4Addr::new(192, 168, 1, 255)),
+            netmask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+        }]
+    }
+
+    #[test]
+    fn test_reply_to_probe() {
+        let src: SocketAddr = "192.168.1.23:50000".parse().unwrap();
+        let reply = reply_to_probe(DISCOVERY_PROBE, src, &lan(), 1236, Some(1237)).unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+        assert_eq!(reply["ip"], "192.168.1.10");
+        assert_eq!(reply["port"], 1236);
+        assert!(reply["features"].as_array().unwrap().iter().any(|f| f == "web_pad"));
+    }
+
+    #[test]
+    fn test_ignore_other_subnet_and_garbage() {
+        let src: SocketAddr = "10.0.0.23:50000".parse().unwrap();
+        assert!(reply_to_probe(DISCOVERY_PROBE, src, &lan(), 1236, None).is_none());
+        let src: SocketAddr = "192.168.1.23:50000".parse().unwrap();
+        assert!(reply_to_probe(b"HELLO", src, &lan(), 1236, None).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::control_handler::{JOG_CC, OP_SCRUB, press};
+use crate::daw_profile::{action_of, ControlAction};
+use crate::daw_state::{DawState, GLOBAL_DAW_STATE};
+use crate::mcu_feedback::{LedState, McuFeedback};
+use crate::message::Message;
+use crate::vpot_handler::{accelerate, send_relative_ticks};
+
+/// 飞梭，把客户端连续的相对转动转换为MCU的飞梭CC 60
+/// 在scrub模式下，DAW会把飞梭的转动解释为擦洗播放，而不是移动播放头
+pub fn handle_jog_message(daw: &str, msg: Message) {
+    if let Message::JogMessage { delta, acceleration, scrub } = msg {
+        set_scrub(daw, scrub == 1);
+        send_relative_ticks(JOG_CC as i8, accelerate(delta, acceleration));
+    }
+}
+
+/// scrub按钮是切换式的，只在客户端请求的状态与scrub按钮灯不同时，按下客户端DAW配置中的scrub按钮
+/// scrub不是按钮音符时（不支持或者是CC、SysEx），无法从按钮灯得知当前状态，所以不切换
+fn set_scrub(daw: &str, on: bool) {
+    let action = match action_of(daw, OP_SCRUB) {
+        Some(action @ ControlAction::Note { .. }) => action,
+        _ => return
+    };
+    if is_scrub_on(&GLOBAL_DAW_STATE.lock().unwrap(), &action) != on {
+        press(&action);
+        on_scrub_pressed(&action);
+    }
+}
+
+fn is_scrub_on(daw_state: &DawState, action: &ControlAction) -> bool {
+    match action {
+        ControlAction::Note { note, .. } => daw_state.button_led(*note) != LedState::Off,
+        _ => false
+    }
+}
+
+/// scrub按钮被按下（飞梭或者Control Message），不等DAW回传，先翻转DawState中的scrub按钮灯
+/// 连接了反馈端口时，DAW回传的按钮灯随后会覆盖它；没有连接时，按钮灯只由这里维护
+pub fn on_scrub_pressed(action: &ControlAction) {
+    if let ControlAction::Note { note, .. } = action {
+        let mut daw_state = GLOBAL_DAW_STATE.lock().unwrap();
+        let state = if is_scrub_on(&daw_state, action) { LedState::Off } else { LedState::On };
+        daw_state.apply(McuFeedback::ButtonLed { note: *note, state });
+    }
+}
+
+#[cfg(test)]
+mod jog_handler_test {
+    use crate::daw_profile::ControlAction;
+    use crate::daw_state::DawState;
+    use crate::jog_handler::is_scrub_on;
+    use crate::mcu_feedback::{LedState, McuFeedback};
+    use crate::vpot_handler::{accelerate, relative_cc_values};
+
+    #[test]
+    fn test_jog_delta_to_cc() {
+        assert_eq!(relative_cc_values(accelerate(2, 0)), vec![0x02]);
+        assert_eq!(relative_cc_values(accelerate(-1, 0)), vec![0x41]);
+        assert!(relative_cc_values(accelerate(0, 100)).is_empty());
+        // 10格，加速100%时变为110格，拆成63 + 47
+        assert_eq!(relative_cc_values(accelerate(10, 100)), vec![0x3F, 0x2F]);
+        assert_eq!(relative_cc_values(accelerate(-10, 100)), vec![0x7F, 0x6F]);
+    }
+
+    #[test]
+    fn test_scrub_state_follows_led() {
+        let scrub = ControlAction::Note { note: 101, channel: 1 };
+        let mut state = DawState::new();
+        assert!(!is_scrub_on(&state, &scrub));
+        state.apply(McuFeedback::ButtonLed { note: 101, state: LedState::Blink });
+        assert!(is_scrub_on(&state, &scrub));
+        state.apply(McuFeedback::ButtonLed { note: 101, state: LedState::Off });
+        assert!(!is_scrub_on(&state, &scrub));
+        assert!(!is_scrub_on(&state, &ControlAction::Unsupported));
+    }
+}
@@ -17,12 +17,30 @@ mod public;
 mod control_handler;
 mod midi_note_to_number;
 mod track_handler;
+mod mcu_feedback;
+mod daw_state;
+mod vpot_handler;
+mod daw_profile;
+mod jog_handler;
+mod notice;
+mod client_registry;
+mod console;
+mod midi_panic;
+mod udp_server;
+mod websocket;
+mod web_pad;
+mod mdns;
+mod discovery;
+mod pairing;
+mod tls;
+mod permission;
+mod config;
+mod scale;
+mod tempo;
 
 #[tokio::main]
 async fn main() {
-    log4rs::init_file("log4rs-config.yaml", Default::default()).unwrap();
-    log::info!("log4rs initialized!");
-    log::info!("starting command line client!");
+    // 日志级别可能来自配置文件或命令行，所以由cmd初始化log4rs
     cmd::startup().await;
     // ui::gui::launch();
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+// MCU反馈解析
+// DAW通过控制端口回传给控制器的MIDI消息，包括LCD文字、时间码显示、V-Pot LED环、电平表、按钮灯以及电动推子位置
+// 这里只负责将原始字节解析为`McuFeedback`，状态的维护由`daw_state`完成
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum McuFeedback {
+    // LCD文字更新，offset是在 2 * 56 个字符中的起始偏移
+    LcdText {
+        offset: u8,
+        text: Vec<u8>
+    },
+    // 时间码/BBT显示，digit从0开始，0代表最右边的一位
+    TimecodeDigit {
+        digit: u8,
+        character: char,
+        dot: bool
+    },
+    // 两位的Assignment显示
+    AssignmentDigit {
+        digit: u8,
+        character: char,
+        dot: bool
+    },
+    // V-Pot LED环，nth从0开始，value是原始的环模式与位置
+    VPotRing {
+        nth: u8,
+        value: u8
+    },
+    // 电平表，nth从0开始，level范围0~12
+    Meter {
+        nth: u8,
+        level: u8
+    },
+    MeterOverload {
+        nth: u8,
+        overload: bool
+    },
+    // 按钮灯，note即按钮对应的音符
+    ButtonLed {
+        note: u8,
+        state: LedState
+    },
+    // 电动推子位置，nth从0开始，8代表Master推子，value为14位
+    Fader {
+        nth: u8,
+        value: u16
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LedState {
+    Off,
+    On,
+    Blink
+}
+
+/// 解析一条完整的MIDI消息，不认识的消息返回None
+pub fn parse_mcu_message(bytes: &[u8]) -> Option<McuFeedback> {
+    if bytes.is_empty() { return None; }
+    let status = bytes[0];
+    match status & 0xF0 {
+        0x90 if bytes.len() >= 3 => {
+            let state = match bytes[2] {
+                0 => LedState::Off,
+                1 => LedState::Blink,
+                _ => LedState::On
+            };
+            Some(McuFeedback::ButtonLed { note: bytes[1], state })
+        }
+        0xB0 if bytes.len() >= 3 => parse_control_change(bytes[1], bytes[2]),
+        0xD0 if bytes.len() >= 2 => {
+            let nth = (bytes[1] & 0x70) >> 4;
+            match bytes[1] & 0x0F {
+                METER_SET_OVERLOAD => Some(McuFeedback::MeterOverload { nth, overload: true }),
+                METER_CLEAR_OVERLOAD => Some(McuFeedback::MeterOverload { nth, overload: false }),
+                level => Some(McuFeedback::Meter { nth, level: level.min(METER_MAX_LEVEL) })
+            }
+        }
+        0xE0 if bytes.len() >= 3 => {
+            let value = (bytes[1] as u16 & 0x7F) | ((bytes[2] as u16 & 0x7F) << 7);
+            Some(McuFeedback::Fader { nth: status & 0x0F, value })
+        }
+        0xF0 if status == 0xF0 => parse_sysex(bytes),
+        _ => None
+    }
+}
+
+fn parse_control_change(cc: u8, value: u8) -> Option<McuFeedback> {
+    match cc {
+        CC_VPOT_RING_FIRST..=CC_VPOT_RING_LAST => Some(McuFeedback::VPotRing { nth: cc - CC_VPOT_RING_FIRST, value }),
+        CC_TIMECODE_FIRST..=CC_TIMECODE_LAST => {
+            let (character, dot) = decode_7seg_char(value);
+            Some(McuFeedback::TimecodeDigit { digit: cc - CC_TIMECODE_FIRST, character, dot })
+        }
+        CC_ASSIGNMENT_FIRST..=CC_ASSIGNMENT_LAST => {
+            let (character, dot) = decode_7seg_char(value);
+            Some(McuFeedback::AssignmentDigit { digit: cc - CC_ASSIGNMENT_FIRST, character, dot })
+        }
+        _ => None
+    }
+}
+
+// F0 00 00 66 <model> <cmd> ... F7
+fn parse_sysex(bytes: &[u8]) -> Option<McuFeedback> {
+    if bytes.len() < 7 || bytes[1..4] != MACKIE_SYSEX_HEADER { return None; }
+    if !MCU_MODEL_IDS.contains(&bytes[4]) { return None; }
+    let body = if bytes[bytes.len() - 1] == 0xF7 { &bytes[6..bytes.len() - 1] } else { &bytes[6..] };
+    match bytes[5] {
+        SYSEX_LCD if !body.is_empty() => {
+            Some(McuFeedback::LcdText { offset: body[0], text: body[1..].to_vec() })
+        }
+        _ => None
+    }
+}
+
+/// 7段数码管显示的字符编码，低6位是字符，第7位代表小数点
+/// 字符小于0x20时代表的是 '@' ~ '_'
+fn decode_7seg_char(value: u8) -> (char, bool) {
+    let dot = value & 0x40 != 0;
+    let c = value & 0x3F;
+    let c = if c < 0x20 { c + 0x40 } else { c };
+    (c as char, dot)
+}
+
+pub const LCD_WIDTH: usize = 56;
+pub const LCD_SIZE: usize = LCD_WIDTH * 2;
+pub const TIMECODE_DIGITS: usize = 10;
+pub const ASSIGNMENT_DIGITS: usize = 2;
+pub const METER_MAX_LEVEL: u8 = 12;
+
+const MACKIE_SYSEX_HEADER: [u8; 3] = [0x00, 0x00, 0x66];
+// 0x14是Mackie Control，0x10是Logic Control
+const MCU_MODEL_IDS: [u8; 2] = [0x14, 0x10];
+const SYSEX_LCD: u8 = 0x12;
+
+const CC_VPOT_RING_FIRST: u8 = 0x30;
+const CC_VPOT_RING_LAST: u8 = 0x37;
+const CC_TIMECODE_FIRST: u8 = 0x40;
+const CC_TIMECODE_LAST: u8 = 0x49;
+const CC_ASSIGNMENT_FIRST: u8 = 0x4A;
+const CC_ASSIGNMENT_LAST: u8 = 0x4B;
+
+const METER_SET_OVERLOAD: u8 = 0x0E;
+const METER_CLEAR_OVERLOAD: u8 = 0x0F;
+
+#[cfg(test)]
+mod mcu_feedback_test {
+    use crate::mcu_feedback::{LedState, McuFeedback, parse_mcu_message};
+
+    #[test]
+    fn test_parse_lcd_sysex() {
+        let msg = [0xF0, 0x00, 0x00, 0x66, 0x14, 0x12, 0x38, b'K', b'i', b'c', b'k', 0xF7];
+        assert_eq!(parse_mcu_message(&msg), Some(McuFeedback::LcdText { offset: 0x38, text: b"Kick".to_vec() }));
+    }
+
+    #[test]
+    fn test_ignore_other_sysex() {
+        let msg = [0xF0, 0x00, 0x00, 0x66, 0x15, 0x12, 0x00, b'K', 0xF7];
+        assert_eq!(parse_mcu_message(&msg), None);
+        let msg = [0xF0, 0x7E, 0x00, 0x06, 0x01, 0xF7];
+        assert_eq!(parse_mcu_message(&msg), None);
+    }
+
+    #[test]
+    fn test_parse_timecode_digit() {
+        // '1' with dot
+        assert_eq!(parse_mcu_message(&[0xB0, 0x42, 0x71]),
+                   Some(McuFeedback::TimecodeDigit { digit: 2, character: '1', dot: true }));
+        // 'A' is encoded as 0x01
+        assert_eq!(parse_mcu_message(&[0xB0, 0x4A, 0x01]),
+                   Some(McuFeedback::AssignmentDigit { digit: 0, character: 'A', dot: false }));
+    }
+
+    #[test]
+    fn test_parse_vpot_ring() {
+        assert_eq!(parse_mcu_message(&[0xB0, 0x33, 0x16]), Some(McuFeedback::VPotRing { nth: 3, value: 0x16 }));
+    }
+
+    #[test]
+    fn test_parse_meter() {
+        assert_eq!(parse_mcu_message(&[0xD0, 0x25]), Some(McuFeedback::Meter { nth: 2, level: 5 }));
+        assert_eq!(parse_mcu_message(&[0xD0, 0x7E]), Some(McuFeedback::MeterOverload { nth: 7, overload: true }));
+        assert_eq!(parse_mcu_message(&[0xD0, 0x7F]), Some(McuFeedback::MeterOverload { nth: 7, overload: false }));
+    }
+
+    #[test]
+    fn test_parse_button_led() {
+        assert_eq!(parse_mcu_message(&[0x90, 0x5E, 0x7F]), Some(McuFeedback::ButtonLed { note: 0x5E, state: LedState::On }));
+        assert_eq!(parse_mcu_message(&[0x90, 0x5F, 0x01]), Some(McuFeedback::ButtonLed { note: 0x5F, state: LedState::Blink }));
+        assert_eq!(parse_mcu_message(&[0x90, 0x5F, 0x00]), Some(McuFeedback::ButtonLed { note: 0x5F, state: LedState::Off }));
+    }
+
+    #[test]
+    fn test_parse_fader() {
+        assert_eq!(parse_mcu_message(&[0xE3, 0x7F, 0x7F]), Some(McuFeedback::Fader { nth: 3, value: 0x3FFF }));
+        assert_eq!(parse_mcu_message(&[0xE8, 0x00, 0x40]), Some(McuFeedback::Fader { nth: 8, value: 0x2000 }));
+    }
+}
@@ -0,0 +1,108 @@
+use std::env;
+use std::fs;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use crate::constants::{SERVER_NAME, SERVER_PLATFORM, SERVER_VERSION};
+
+/// 通过mDNS/DNS-SD在局域网内广播服务，App可以直接列出局域网内的服务端，不再需要扫码或者手动输入ip
+pub const SERVICE_TYPE: &str = "_vpad._tcp.local.";
+
+/// 持有mDNS守护线程，被丢弃时注销服务并停止广播
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertiser {
+    pub fn start(port: u16, ws_port: Option<u16>) -> Result<MdnsAdvertiser, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+        let info = service_info(&host_name(), port, ws_port)?;
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        log::info!("Advertising {} over mDNS", fullname);
+        Ok(MdnsAdvertiser { daemon, fullname })
+    }
+}
+
+impl Drop for MdnsAdvertiser {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// 实例名形如 `VPadServer (my-pc)`，TXT记录中带上版本、平台、名称以及各个端口
+/// 地址由mdns-sd根据当前的网络接口自动维护，网络变化后不需要重新注册
+fn service_info(host: &str, port: u16, ws_port: Option<u16>) -> Result<ServiceInfo, mdns_sd::Error> {
+    let mut properties = vec![
+        ("version", SERVER_VERSION.to_string()),
+        ("platform", SERVER_PLATFORM.to_string()),
+        ("name", SERVER_NAME.to_string()),
+        ("port", port.to_string()),
+    ];
+    if let Some(ws_port) = ws_port {
+        properties.push(("ws_port", ws_port.to_string()));
+    }
+    let instance = format!("{} ({})", SERVER_NAME, host);
+    let host_name = format!("{}.local.", host);
+    Ok(ServiceInfo::new(SERVICE_TYPE, &instance, &host_name, "", port, &properties[..])?.enable_addr_auto())
+}
+
+/// 主机名只能包含字母、数字和'-'，取不到时使用服务名
+fn host_name() -> String {
+    let name = env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name: String = name.trim().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if name.is_empty() { SERVER_NAME.to_string() } else { name }
+}
+
+#[cfg(test)]
+mod mdns_test {
+    use std::time::{Duration, Instant};
+    use mdns_sd::{ServiceDaemon, ServiceEvent};
+    use crate::constants::SERVER_VERSION;
+    use crate::mdns::{host_name, MdnsAdvertiser, SERVICE_TYPE, service_info};
+
+    #[test]
+    fn test_service_info() {
+        let info = service_info("my-pc", 1236, Some(1237)).unwrap();
+        assert_eq!(info.get_fullname(), "VPadServer (my-pc)._vpad._tcp.local.");
+        assert_eq!(info.get_hostname(), "my-pc.local.");
+        assert_eq!(info.get_property_val_str("version"), Some(SERVER_VERSION));
+        assert_eq!(info.get_property_val_str("port"), Some("1236"));
+        assert_eq!(info.get_property_val_str("ws_port"), Some("1237"));
+        assert!(service_info("my-pc", 1236, None).unwrap().get_property("ws_port").is_none());
+    }
+
+    #[test]
+    fn test_host_name_is_a_valid_label() {
+        assert!(host_name().chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    }
+
+    /// 在本机上注册服务，再用另一个守护线程浏览，应当能解析出TXT记录
+    /// 需要主机支持组播，容器和CI中通常不支持，用`cargo test -- --ignored`手动运行
+    #[test]
+    #[ignore]
+    fn test_advertise_and_browse() {
+        let advertiser = MdnsAdvertiser::start(41236, Some(41237)).unwrap();
+        let browser = ServiceDaemon::new().unwrap();
+        let receiver = browser.browse(SERVICE_TYPE).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut resolved = None;
+        while let Ok(event) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if info.get_fullname() == advertiser.fullname {
+                    resolved = Some(info);
+                    break;
+                }
+            }
+        }
+        let _ = browser.shutdown();
+        let info = resolved.expect("service is not resolved");
+        assert_eq!(info.get_port(), 41236);
+        assert_eq!(info.get_property_val_str("ws_port"), Some("41237"));
+    }
+}
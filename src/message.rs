@@ -1,20 +1,36 @@
 use std::borrow::BorrowMut;
+use std::net::SocketAddr;
 use crate::arp_handler::GLOBAL_ARP_HANDLER;
 use crate::chord_handler::GLOBAL_CHORD_HANDLER;
+use crate::client_registry::{ClientInfo, ClientSettings, GLOBAL_CLIENT_REGISTRY};
 use crate::constants::*;
 use crate::control_handler::{DawType, handle_control_msg};
-use crate::midi_connect::{GLOBAL_MIDI_CONNECTOR};
+use crate::daw_profile::profile_exists;
+use crate::daw_state::GLOBAL_DAW_STATE;
+use crate::midi_connect::{GLOBAL_MIDI_CONNECTOR, resolve_channel};
+use crate::midi_panic::midi_panic;
+use crate::notice::Notice;
+use crate::permission::{restriction_of, Role};
 use crate::message::Message::*;
 use crate::pitch_wheel;
+use crate::scale::snap_to_scale;
 use crate::server::VPadMessageContext;
+use crate::tempo::effective_bpm;
+use crate::jog_handler::handle_jog_message;
 use crate::track_handler::handle_track_message;
+use crate::udp_server::udp_session_port;
+use crate::vpot_handler::handle_vpot_message;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Message {
     HandShake {
         name: String,
         platform: String,
+        // version 2新增，客户端希望使用的DAW
+        daw: Option<i8>,
+        // version 3新增，服务端开启配对时出示的PIN或者配对密钥
+        token: Option<String>,
     },
     Midi {
         note: i8,
@@ -66,36 +82,130 @@ pub enum Message {
     TrackMessage {
         nth: i8,
         state: i8,
-        value: i8
+        value: i8,
+        // version 2新增，14位的推子值，旧版本客户端不会发送该字段
+        fine_value: Option<i16>
+    },
+    // 客户端发送的是空消息，用于查询，服务端回复时填充各字段
+    DawStateMessage {
+        timecode: String,
+        assignment: String,
+        lcd_upper: String,
+        lcd_lower: String
+    },
+    VPotMessage {
+        nth: i8,
+        state: i8,
+        ticks: i8,
+        acceleration: i8
+    },
+    // 切换当前客户端使用的DAW，服务端回复实际生效的DAW
+    DawSelectMessage {
+        daw: i8,
+        // version 2新增，按名字选择DAW配置（包括用户自定义的配置）
+        name: Option<String>
+    },
+    JogMessage {
+        delta: i8,
+        acceleration: i8,
+        scrub: i8
+    },
+    // 服务端无法执行客户端的请求时回复，code见notice.rs，op是请求的消息类型，value是引起问题的字段值
+    NoticeMessage {
+        code: i8,
+        op: i8,
+        value: i8,
+        text: String
+    },
+    // 客户端请求开启UDP通道，服务端回复session与UDP端口，port为0代表服务端没有开启UDP通道
+    UdpSessionMessage {
+        session: i32,
+        port: i16
+    },
+    // 客户端用PIN配对成功后，服务端发送配对密钥，客户端之后握手时出示密钥
+    PairMessage {
+        key: String
+    },
+    // MIDI panic，空消息
+    PanicMessage,
+    // 服务端即将关闭，空消息，只由服务端发送
+    ShutdownMessage,
+    // 心跳，收到Ping的一方用相同的seq回复Pong
+    PingMessage {
+        seq: i16
+    },
+    PongMessage {
+        seq: i16
+    },
+    // 当前bank中8个轨道的名字，客户端发送空消息用于查询
+    TrackNameMessage {
+        bank: i8,
+        names: Vec<String>
     }
 }
 
 
 impl Message {
-    pub fn handle_and_return<'a>(self, ctx: &'a VPadMessageContext) -> Option<Message> {
+    pub fn handle_and_return<'a>(self, ctx: &'a mut VPadMessageContext) -> Option<Message> {
+        // 在消息到达handle_control_msg、handle_track_message等处理函数之前检查客户端的角色
+        if let Some((op, required)) = restriction_of(&self) {
+            let role = GLOBAL_CLIENT_REGISTRY.lock().unwrap().role(ctx.client_id);
+            if role < required {
+                return notice_of(Err(Notice::PermissionDenied(required)), op);
+            }
+        }
+        self.apply_play_settings().handle(ctx)
+    }
+
+    fn handle(self, ctx: &mut VPadMessageContext) -> Option<Message> {
         match self {
-            HandShake { .. } => {
+            HandShake { name, daw, .. } => {
+                // 握手中选择DAW与DawSelect Message需要同样的角色，角色不够时忽略，保留默认的DAW
+                let may_select_daw = GLOBAL_CLIENT_REGISTRY.lock().unwrap().role(ctx.client_id) >= Role::Full;
+                // 同名的客户端重连时，恢复它上次的设置
+                let settings = GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_name(ctx.client_id, name)
+                    .filter(|_| may_select_daw);
+                if let Some(settings) = settings {
+                    if select_daw_profile(ctx, settings.daw).is_err() {
+                        log::warn!("daw profile of {:?} is gone, fallback to {}", ctx.addr, ctx.daw);
+                    }
+                }
+                // version 3的客户端不想选择DAW时发送-1
+                if let Some(daw) = daw.filter(|daw| *daw >= 0) {
+                    // 握手只能回复一条消息，选择失败时只记录日志，客户端可以之后用DawSelect Message重试
+                    if !may_select_daw {
+                        log::warn!("{:?} is not allowed to select daw while handshaking", ctx.addr);
+                    } else if let Err(notice) = select_daw(ctx, daw) {
+                        log::error!("cannot select daw while handshaking, {}", notice);
+                    }
+                }
+                save_settings(ctx);
                 Some(HandShake {
                     name: SERVER_NAME.into(),
-                    platform: SERVER_PLATFORM.into()
+                    platform: SERVER_PLATFORM.into(),
+                    daw: None,
+                    token: None
                 })
             },
             Midi {note, velocity, state, channel} => {
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_note_held(ctx.client_id, channel, note, state == 1 && velocity > 0);
                 let mut midi_connector = GLOBAL_MIDI_CONNECTOR.lock().unwrap();
                 midi_connector.borrow_mut().midi_note_message_with_channel_number(note, velocity, state, channel);
                 None
             },
-            Arp { note, .. } => {
-                let identifier = format!("{}:{} on {}", ctx.addr.ip().to_string(), ctx.addr.port().to_string(), &note);
+            Arp { note, state, .. } => {
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_arp_active(ctx.client_id, note, state == 1);
+                let identifier = format!("{}{}", client_identifier_prefix(&ctx.addr), &note);
                 GLOBAL_ARP_HANDLER.handle(identifier, self);
                 None
             },
-            Chord { note, .. } => {
-                let identifier = format!("{}:{} on {}", ctx.addr.ip().to_string(), ctx.addr.port().to_string(), &note);
-                GLOBAL_CHORD_HANDLER.handle(identifier, self);
-                None
+            Chord { note, state, .. } => {
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_chord_active(ctx.client_id, note, state == 1);
+                let identifier = format!("{}{}", client_identifier_prefix(&ctx.addr), &note);
+                notice_of(GLOBAL_CHORD_HANDLER.handle(identifier, self), CHORD_OP)
             },
             PitchWheel { pos, prev_pos, channel} => {
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().set_pitch_bent(ctx.client_id, channel, pos != PITCH_WHEEL_CENTER);
                 pitch_wheel::move_to_smoothly(prev_pos, pos, channel);
                 None
             },
@@ -105,14 +215,145 @@ impl Message {
                 None
             },
             ControlMessage { .. } => {
-                handle_control_msg(DawType::McuDefault, self);
-                None
+                notice_of(handle_control_msg(&ctx.daw, self), CONTROL_OP)
             },
             TrackMessage { .. } => {
-                handle_track_message(self);
+                notice_of(handle_track_message(self), TRACK_OP)
+            },
+            DawStateMessage { .. } => {
+                let daw_state = GLOBAL_DAW_STATE.lock().unwrap();
+                Some(DawStateMessage {
+                    timecode: daw_state.timecode_text(),
+                    assignment: daw_state.assignment_text(),
+                    lcd_upper: daw_state.lcd_line(0),
+                    lcd_lower: daw_state.lcd_line(1)
+                })
+            },
+            VPotMessage { .. } => {
+                notice_of(handle_vpot_message(self), VPOT_OP)
+            },
+            DawSelectMessage { daw, name } => {
+                let selected = match name {
+                    Some(name) if !name.is_empty() => select_daw_profile(ctx, name),
+                    _ => select_daw(ctx, daw)
+                };
+                if selected.is_err() {
+                    return notice_of(selected, DAW_SELECT_OP);
+                }
+                save_settings(ctx);
+                Some(DawSelectMessage {
+                    daw: DawType::from_profile_name(&ctx.daw).map(|daw| daw.code()).unwrap_or(-1),
+                    name: Some(ctx.daw.clone())
+                })
+            },
+            JogMessage { .. } => {
+                handle_jog_message(&ctx.daw, self);
                 None
+            },
+            NoticeMessage { .. } | PairMessage { .. } | ShutdownMessage => None,
+            UdpSessionMessage { .. } => {
+                let port = udp_session_port();
+                let session = if port == 0 { None } else { GLOBAL_CLIENT_REGISTRY.lock().unwrap().open_udp_session(ctx.client_id) };
+                match session {
+                    Some(session) => {
+                        log::info!("{:?} opened udp session {}", ctx.addr, session);
+                        Some(UdpSessionMessage { session: session as i32, port: port as i16 })
+                    }
+                    None => Some(UdpSessionMessage { session: 0, port: 0 })
+                }
+            },
+            PingMessage { seq } => {
+                ctx.heartbeat = true;
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().enable_heartbeat(ctx.client_id);
+                Some(PongMessage { seq })
+            },
+            PongMessage { seq } => {
+                ctx.heartbeat = true;
+                if let Some(rtt) = GLOBAL_CLIENT_REGISTRY.lock().unwrap().finish_ping(ctx.client_id, seq) {
+                    log::debug!("rtt of {:?} is {:?}", ctx.addr, rtt);
+                }
+                None
+            },
+            PanicMessage => {
+                log::warn!("{:?} requested a MIDI panic", ctx.addr);
+                midi_panic();
+                None
+            },
+            TrackNameMessage { .. } => {
+                Some(GLOBAL_DAW_STATE.lock().unwrap().track_name_message())
+            }
+        }
+    }
+
+    /// 应用配置中的演奏设置：channel为0时使用默认通道，音符（琶音与和弦的根音）对齐到音阶，按速度来源替换bpm
+    /// 在记录按下的音符之前应用，所以断开连接时松开的是实际发出的音符
+    fn apply_play_settings(mut self) -> Message {
+        match &mut self {
+            Midi { note, channel, .. } => {
+                *note = snap_to_scale(*note);
+                *channel = resolve_channel(*channel);
             }
+            Arp { note, bpm, channel, .. } | Chord { note, bpm, channel, .. } => {
+                *note = snap_to_scale(*note);
+                *bpm = effective_bpm(*bpm);
+                *channel = resolve_channel(*channel);
+            }
+            PitchWheel { channel, .. } => *channel = resolve_channel(*channel),
+            CC { channel2, .. } => *channel2 = resolve_channel(*channel2),
+            _ => {}
         }
+        self
     }
 }
 
+/// 琶音器和和弦的识别符前缀，识别符是`ip:port on note`，同一个客户端的所有任务共享该前缀
+fn client_identifier_prefix(addr: &SocketAddr) -> String {
+    format!("{}:{} on ", addr.ip(), addr.port())
+}
+
+/// 客户端断开时，松开它按下的所有音符，停止它的琶音器与和弦，并把弯音轮复位
+/// 避免Wi-Fi中断时DAW里留下卡住的音符或者停不下来的琶音
+pub fn release_client(client: &ClientInfo) {
+    let prefix = client_identifier_prefix(&client.addr);
+    GLOBAL_ARP_HANDLER.stop_tasks_with_prefix(&prefix);
+    GLOBAL_CHORD_HANDLER.stop_tasks_with_prefix(&prefix);
+    let mut midi_connector = GLOBAL_MIDI_CONNECTOR.lock().unwrap();
+    if !midi_connector.is_connected() { return; }
+    for (channel, note) in &client.held_notes {
+        midi_connector.midi_note_message_with_channel_number(*note, 0, 0, *channel);
+    }
+    for channel in &client.bent_channels {
+        midi_connector.pitch_wheel_message_with_channel_number(PITCH_WHEEL_CENTER, *channel);
+    }
+}
+
+const PITCH_WHEEL_CENTER: i8 = 64;
+
+/// 请求无法执行时，把原因转换为回复给客户端的Notice Message
+fn notice_of(result: Result<(), Notice>, op: i8) -> Option<Message> {
+    match result {
+        Ok(()) => None,
+        Err(notice) => {
+            log::error!("cannot handle message {}, {}", op, notice);
+            Some(notice.to_message(op))
+        }
+    }
+}
+
+fn save_settings(ctx: &VPadMessageContext) {
+    GLOBAL_CLIENT_REGISTRY.lock().unwrap().save_settings(ctx.client_id, ClientSettings { daw: ctx.daw.clone() });
+}
+
+fn select_daw(ctx: &mut VPadMessageContext, daw: i8) -> Result<(), Notice> {
+    let daw = DawType::from_code(daw).ok_or(Notice::InvalidDaw(daw))?;
+    select_daw_profile(ctx, daw.profile_name())
+}
+
+fn select_daw_profile(ctx: &mut VPadMessageContext, name: String) -> Result<(), Notice> {
+    if !profile_exists(&name) {
+        return Err(Notice::UnknownDawProfile(name));
+    }
+    log::info!("{:?} selected daw profile {}", ctx.addr, name);
+    ctx.daw = name;
+    Ok(())
+}
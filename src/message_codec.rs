@@ -27,7 +27,7 @@ impl codec::Encoder<Message> for MessageCodec {
 
     fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         match message {
-            HandShake {name, platform} => {
+            HandShake {name, platform, ..} => {
                 let name = name.as_bytes();
                 let platform = platform.as_bytes();
 
@@ -36,6 +36,67 @@ impl codec::Encoder<Message> for MessageCodec {
                 dst.put_string(name);
                 dst.put_string(platform);
             }
+            DawStateMessage { timecode, assignment, lcd_upper, lcd_lower } => {
+                put_message(dst, DAW_STATE_OP, |body| {
+                    body.put_string(timecode.as_bytes());
+                    body.put_string(assignment.as_bytes());
+                    body.put_string(lcd_upper.as_bytes());
+                    body.put_string(lcd_lower.as_bytes());
+                });
+            }
+            DawSelectMessage { daw, name } => {
+                put_message(dst, DAW_SELECT_OP, |body| {
+                    body.put_i8(daw);
+                    if let Some(name) = name {
+                        body.put_string(name.as_bytes());
+                    }
+                });
+            }
+            TrackMessage { nth, state, value, fine_value } => {
+                put_message(dst, TRACK_OP, |body| {
+                    body.put_i8(nth);
+                    body.put_i8(state);
+                    body.put_i8(value);
+                    if let Some(fine_value) = fine_value {
+                        body.put_i16(fine_value);
+                    }
+                });
+            }
+            NoticeMessage { code, op, value, text } => {
+                put_message(dst, NOTICE_OP, |body| {
+                    body.put_i8(code);
+                    body.put_i8(op);
+                    body.put_i8(value);
+                    body.put_string(text.as_bytes());
+                });
+            }
+            UdpSessionMessage { session, port } => {
+                put_message(dst, UDP_SESSION_OP, |body| {
+                    body.put_i32(session);
+                    body.put_i16(port);
+                });
+            }
+            PairMessage { key } => {
+                put_message(dst, PAIR_OP, |body| body.put_string(key.as_bytes()));
+            }
+            ShutdownMessage => {
+                put_message(dst, SHUTDOWN_OP, |_| {});
+            }
+            PingMessage { seq } => {
+                put_message(dst, PING_OP, |body| body.put_i16(seq));
+            }
+            PongMessage { seq } => {
+                put_message(dst, PONG_OP, |body| body.put_i16(seq));
+            }
+            TrackNameMessage { bank, names } => {
+                put_message(dst, TRACK_NAME_OP, |body| {
+                    body.put_i8(bank);
+                    body.put_i8(names.len() as i8);
+                    for name in names {
+                        body.put_string(name.as_bytes());
+                    }
+                });
+            }
             _ => {
 
             }
@@ -58,12 +119,11 @@ impl codec::Decoder for MessageCodec {
             // return Err(DecodeError("Incompleted Message 1"));
         }
 
-        let first_two = &src[0..2];
-        let remaind_bytes_cnt: i16 = first_two[0] as i16;
-        let remaind_bytes_cnt: i16 = remaind_bytes_cnt << 8 | first_two[1] as i16;
+        // content_bytes是无符号的
+        let remaind_bytes_cnt = u16::from_be_bytes([src[0], src[1]]) as usize;
 
         // 如果消息不完整
-        if remaind_bytes_cnt as usize > src.len() - 2 {
+        if remaind_bytes_cnt > src.len() - 2 {
             log::error!("Incompleted Message 2. Message Length => {}, src.len() => {}", remaind_bytes_cnt + 2, src.len());
             return Ok(None);
             // return Err(DecodeError("Incompleted Message 2"));
@@ -72,81 +132,123 @@ impl codec::Decoder for MessageCodec {
         src.advance(2);
 
         // 分割当前消息和下一条消息
-        let mut remaind_bytes = src.split_to(remaind_bytes_cnt as usize);
+        let mut remaind_bytes = src.split_to(remaind_bytes_cnt);
         log::debug!("this_message_len:{}, remaind:{}", remaind_bytes.len(), src.len());
 
         // 获取操作码
-        let op = remaind_bytes.get_i8();
+        let op = remaind_bytes.checked_i8()?;
 
         Ok(match op {
             HANDSHAKE_OP => {
                 Some(HandShake {
-                    name: remaind_bytes.get_string(),
-                    platform: remaind_bytes.get_string()
+                    name: remaind_bytes.checked_string()?,
+                    platform: remaind_bytes.checked_string()?,
+                    daw: if remaind_bytes.remaining() >= 1 { Some(remaind_bytes.checked_i8()?) } else { None },
+                    token: if remaind_bytes.remaining() >= 1 { Some(remaind_bytes.checked_string()?) } else { None }
                 })
             }
             MIDI_OP => {
                 Some(Midi {
-                    note: remaind_bytes.get_i8(),
-                    velocity: remaind_bytes.get_i8(),
-                    state: remaind_bytes.get_i8(),
-                    channel: remaind_bytes.get_i8()
+                    note: remaind_bytes.checked_i8()?,
+                    velocity: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    channel: remaind_bytes.checked_i8()?
                 })
             }
             ARP_OP => {
                 Some(Arp {
-                    note: remaind_bytes.get_i8(),
-                    velocity: remaind_bytes.get_i8(),
-                    state: remaind_bytes.get_i8(),
-                    method: remaind_bytes.get_i8(),
-                    rate: remaind_bytes.get_i8(),
-                    swing_pct: remaind_bytes.get_i8(),
-                    up_note_cnt: remaind_bytes.get_i8(),
-                    velocity_automation: remaind_bytes.get_i8(),
-                    dynamic_pct: remaind_bytes.get_i16(),
-                    bpm: remaind_bytes.get_i16(),
-                    channel: remaind_bytes.get_i8()
+                    note: remaind_bytes.checked_i8()?,
+                    velocity: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    method: remaind_bytes.checked_i8()?,
+                    rate: remaind_bytes.checked_i8()?,
+                    swing_pct: remaind_bytes.checked_i8()?,
+                    up_note_cnt: remaind_bytes.checked_i8()?,
+                    velocity_automation: remaind_bytes.checked_i8()?,
+                    dynamic_pct: remaind_bytes.checked_i16()?,
+                    bpm: remaind_bytes.checked_i16()?,
+                    channel: remaind_bytes.checked_i8()?
                 })
             }
             CHORD_OP => {
                 Some(Chord {
-                    note: remaind_bytes.get_i8(),
-                    velocity: remaind_bytes.get_i8(),
-                    state: remaind_bytes.get_i8(),
-                    chord_type: remaind_bytes.get_i8(),
-                    chord_level: remaind_bytes.get_i8(),
-                    transpose: remaind_bytes.get_i8(),
-                    arp_delay: remaind_bytes.get_i8(),
-                    bpm: remaind_bytes.get_i16(),
-                    channel: remaind_bytes.get_i8()
+                    note: remaind_bytes.checked_i8()?,
+                    velocity: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    chord_type: remaind_bytes.checked_i8()?,
+                    chord_level: remaind_bytes.checked_i8()?,
+                    transpose: remaind_bytes.checked_i8()?,
+                    arp_delay: remaind_bytes.checked_i8()?,
+                    bpm: remaind_bytes.checked_i16()?,
+                    channel: remaind_bytes.checked_i8()?
                 })
             }
             PITCHWHEEL_OP => {
                 Some(PitchWheel {
-                    pos: remaind_bytes.get_i8(),
-                    prev_pos: remaind_bytes.get_i8(),
-                    channel: remaind_bytes.get_i8()
+                    pos: remaind_bytes.checked_i8()?,
+                    prev_pos: remaind_bytes.checked_i8()?,
+                    channel: remaind_bytes.checked_i8()?
                 })
             }
             CC_OP => {
                 Some(CC {
-                    channel: remaind_bytes.get_i8(),
-                    value: remaind_bytes.get_i8(),
-                    channel2: remaind_bytes.get_i8()
+                    channel: remaind_bytes.checked_i8()?,
+                    value: remaind_bytes.checked_i8()?,
+                    channel2: remaind_bytes.checked_i8()?
                 })
             }
             CONTROL_OP => {
                 Some(ControlMessage {
-                    operation: remaind_bytes.get_i8(),
-                    state: remaind_bytes.get_i8(),
-                    auto_close: remaind_bytes.get_i8()
+                    operation: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    auto_close: remaind_bytes.checked_i8()?
                 })
             }
             TRACK_OP => {
                 Some(TrackMessage {
-                    nth: remaind_bytes.get_i8(),
-                    state: remaind_bytes.get_i8(),
-                    value: remaind_bytes.get_i8()
+                    nth: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    value: remaind_bytes.checked_i8()?,
+                    fine_value: if remaind_bytes.remaining() >= 2 { Some(remaind_bytes.checked_i16()?) } else { None }
+                })
+            }
+            DAW_STATE_OP => {
+                Some(DawStateMessage {
+                    timecode: String::new(),
+                    assignment: String::new(),
+                    lcd_upper: String::new(),
+                    lcd_lower: String::new()
+                })
+            }
+            TRACK_NAME_OP => {
+                Some(TrackNameMessage {
+                    bank: 0,
+                    names: Vec::new()
+                })
+            }
+            VPOT_OP => {
+                Some(VPotMessage {
+                    nth: remaind_bytes.checked_i8()?,
+                    state: remaind_bytes.checked_i8()?,
+                    ticks: remaind_bytes.checked_i8()?,
+                    acceleration: remaind_bytes.checked_i8()?
+                })
+            }
+            DAW_SELECT_OP => {
+                Some(DawSelectMessage {
+                    daw: remaind_bytes.checked_i8()?,
+                    name: if remaind_bytes.remaining() >= 1 { Some(remaind_bytes.checked_string()?) } else { None }
+                })
+            }
+            PANIC_OP => Some(PanicMessage),
+            UDP_SESSION_OP => Some(UdpSessionMessage { session: 0, port: 0 }),
+            PING_OP => Some(PingMessage { seq: remaind_bytes.checked_i16()? }),
+            PONG_OP => Some(PongMessage { seq: remaind_bytes.checked_i16()? }),
+            JOG_OP => {
+                Some(JogMessage {
+                    delta: remaind_bytes.checked_i8()?,
+                    acceleration: remaind_bytes.checked_i8()?,
+                    scrub: remaind_bytes.checked_i8()?
                 })
             }
             _ => {
@@ -157,23 +259,44 @@ impl codec::Decoder for MessageCodec {
     }
 }
 
-trait GetString {
-    fn get_string(&mut self) -> String;
+// 先写入操作码和消息体，再在头部补上content_bytes
+fn put_message<F>(dst: &mut BytesMut, op: i8, f: F)
+    where F: FnOnce(&mut BytesMut) {
+    let mut body = BytesMut::new();
+    body.put_i8(op);
+    f(&mut body);
+    dst.put_i16(body.len() as i16);
+    dst.put(body);
+}
+
+/// 消息体中的字段都是客户端发来的，长度不够或者字符串不是UTF-8时返回错误，而不是panic
+trait CheckedGet {
+    fn checked_i8(&mut self) -> Result<i8, MessageCodecError>;
+    fn checked_i16(&mut self) -> Result<i16, MessageCodecError>;
+    fn checked_string(&mut self) -> Result<String, MessageCodecError>;
 }
 trait PutString {
     fn put_string(&mut self, string: &[u8]);
 }
 
-impl GetString for BytesMut {
-    fn get_string(&mut self) -> String {
-        let len = self.get_i8();
-        let mut vec = Vec::with_capacity(len as usize);
-        for _ in 0..len {
-            vec.push(self.get_u8());
-        }
-        String::from_utf8(vec).unwrap()
+impl CheckedGet for BytesMut {
+    fn checked_i8(&mut self) -> Result<i8, MessageCodecError> {
+        if self.remaining() < 1 { return Err(DecodeError("Message is too short")); }
+        Ok(self.get_i8())
     }
 
+    fn checked_i16(&mut self) -> Result<i16, MessageCodecError> {
+        if self.remaining() < 2 { return Err(DecodeError("Message is too short")); }
+        Ok(self.get_i16())
+    }
+
+    // 长度是无符号的
+    fn checked_string(&mut self) -> Result<String, MessageCodecError> {
+        let len = self.checked_i8()? as u8 as usize;
+        if self.remaining() < len { return Err(DecodeError("String is too short")); }
+        let vec = self.split_to(len).to_vec();
+        String::from_utf8(vec).map_err(|_| DecodeError("String is not utf-8"))
+    }
 }
 
 impl PutString for BytesMut {
@@ -184,3 +307,40 @@ impl PutString for BytesMut {
         }
     }
 }
+
+#[cfg(test)]
+mod message_codec_test {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use crate::message::Message;
+    use crate::message_codec::MessageCodec;
+
+    fn decode(bytes: &[u8]) -> Result<Option<Message>, super::MessageCodecError> {
+        MessageCodec{}.decode(&mut BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn test_malformed_frames_are_errors() {
+        // 空消息体，没有操作码
+        assert!(decode(&[0, 0]).is_err());
+        // Midi Message少了字段
+        assert!(decode(&[0, 3, 2, 60, 100]).is_err());
+        // 字符串长度超出消息体
+        assert!(decode(&[0, 3, 1, 10, b'a']).is_err());
+        // 字符串不是UTF-8
+        assert!(decode(&[0, 5, 1, 1, 0xFF, 1, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_decode_midi_and_handshake() {
+        assert!(matches!(decode(&[0, 5, 2, 60, 100, 1, 1]).unwrap(), Some(Message::Midi { note: 60, velocity: 100, state: 1, channel: 1 })));
+        let handshake = [0, 9, 1, 3, b'p', b'a', b'd', 3, b'W', b'e', b'b'];
+        match decode(&handshake).unwrap() {
+            Some(Message::HandShake { name, platform, daw: None, token: None }) => {
+                assert_eq!(name, "pad");
+                assert_eq!(platform, "Web");
+            }
+            other => panic!("not a handshake: {:?}", other)
+        }
+    }
+}
@@ -1,29 +1,34 @@
+use std::collections::BTreeSet;
 use std::string::ToString;
 use std::sync::{Mutex};
 use lazy_static::lazy_static;
 use midi_control::{Channel, MidiMessageSend};
-use midir::{ConnectError, InitError, MidiOutput, MidiOutputConnection};
+use midir::{ConnectError, InitError, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use crate::{midi_connect::MidiConnectorError::PortNotFoundError};
 
 pub type Result<T> = std::result::Result<T, MidiConnectorError>;
 
 pub struct MidiConnector {
     name: String,
-    connection: Option<MidiOutputConnection>
+    connection: Option<MidiOutputConnection>,
+    // 每个通道上按下还没有松开的音符，下标是通道（0~15），MIDI panic时用来逐个发送note off
+    active_notes: [BTreeSet<u8>; 16]
 }
 
 impl MidiConnector {
     pub fn new(name: String) -> MidiConnector {
         MidiConnector {
             name,
-            connection: None
+            connection: None,
+            active_notes: Default::default()
         }
     }
 
     pub fn with_connection(name: String, connection: MidiOutputConnection) -> MidiConnector {
         MidiConnector {
             name: name,
-            connection: Some(connection)
+            connection: Some(connection),
+            active_notes: Default::default()
         }
     }
 
@@ -65,6 +70,13 @@ impl MidiConnector {
         self.connection.is_some()
     }
 
+    /// 关闭端口，之后is_connected为false
+    pub fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close();
+        }
+    }
+
     pub fn midi_note_message(&mut self, note: i8, velocity: i8, state: i8) {
         self.midi_note_message_with_channel(note, velocity, state, Channel::Ch1);
     }
@@ -74,6 +86,7 @@ impl MidiConnector {
     }
 
     pub fn midi_note_message_with_channel(&mut self, note: i8, velocity: i8, state: i8, channel: Channel) {
+        self.track_note(channel as usize, note as u8, state != 0 && velocity > 0);
         let message = if state == 0 {
             midi_control::note_off(channel, note as u8, velocity as u8)
         } else {
@@ -96,7 +109,50 @@ impl MidiConnector {
             midi_control::pitch_bend(ch, pos)
         ).expect("error to send pitchwheel message");
     }
+    /// 以完整的14位精度发送pitch bend，value的范围是0~16383
+    pub fn pitch_wheel_14bit_message_with_channel(&mut self, value: i16, ch: Channel) {
+        let value = value.clamp(0, 0x3FFF) as u16;
+        self.connection.as_mut().unwrap().send_message(
+            midi_control::pitch_bend(ch, value)
+        ).expect("error to send pitchwheel message");
+    }
+
+
+    fn track_note(&mut self, channel: usize, note: u8, on: bool) {
+        if let Some(notes) = self.active_notes.get_mut(channel) {
+            if on { notes.insert(note); } else { notes.remove(&note); }
+        }
+    }
+
+    /// 某个通道（0~15）上按下还没有松开的音符
+    pub fn active_notes(&self, channel: usize) -> Vec<u8> {
+        self.active_notes.get(channel).map(|notes| notes.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// MIDI panic，在全部16个通道上松开所有记录的音符，发送All Sound Off（CC 120）和All Notes Off（CC 123），
+    /// 并复位弯音轮与延音踏板
+    pub fn panic(&mut self) {
+        let active_notes = std::mem::take(&mut self.active_notes);
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => return
+        };
+        for (channel, notes) in active_notes.iter().enumerate() {
+            let ch = Channel::from(channel as u8);
+            for note in notes {
+                let _ = connection.send_message(midi_control::note_off(ch, *note, 0));
+            }
+            let _ = connection.send_message(midi_control::control_change(ch, CC_ALL_SOUND_OFF, 0));
+            let _ = connection.send_message(midi_control::control_change(ch, CC_ALL_NOTES_OFF, 0));
+            let _ = connection.send_message(midi_control::control_change(ch, CC_SUSTAIN, 0));
+            let _ = connection.send_message(midi_control::pitch_bend(ch, PITCH_BEND_CENTER));
+        }
+    }
 
+    /// 直接发送原始的MIDI字节，用于SysEx或DAW配置中自定义的消息序列
+    pub fn raw_message(&mut self, bytes: &[u8]) {
+        self.connection.as_mut().unwrap().send(bytes).expect("error to send raw message");
+    }
 
     pub fn cc_message(&mut self, channel: i8, value: i8) {
         self.cc_message_with_channel(channel, value, Channel::Ch1);
@@ -112,6 +168,69 @@ impl MidiConnector {
 
 }
 
+/// MidiConnector的输入端，用于接收DAW通过控制端口发回的反馈
+pub struct MidiInputConnector {
+    name: String,
+    connection: Option<MidiInputConnection<()>>
+}
+
+impl MidiInputConnector {
+    pub fn new(name: String) -> MidiInputConnector {
+        MidiInputConnector {
+            name,
+            connection: None
+        }
+    }
+
+    fn open_input() -> Result<MidiInput> {
+        Ok(MidiInput::new("client")?)
+    }
+
+    /// 获取并返回当前输入port列表
+    pub fn port_list() -> Result<Vec<String>> {
+        let input = MidiInputConnector::open_input()?;
+        let mut port_name_list: Vec<String> = Vec::with_capacity(input.port_count());
+        for port in input.ports() {
+            if let Ok(port_name) = input.port_name(&port) {
+                port_name_list.push(port_name);
+            }
+        }
+
+        Ok(port_name_list)
+    }
+
+    /// 连接名为port_name的输入port，收到的每条消息都会交给callback
+    pub fn connect_port<F>(&mut self, port_name: String, mut callback: F) -> Result<()>
+        where F: FnMut(&[u8]) + Send + 'static {
+        let input = MidiInputConnector::open_input()?;
+        for port in input.ports() {
+            if let Ok(this_port_name) = input.port_name(&port) {
+                if port_name == this_port_name {
+                    self.connection = Some(input.connect(&port, &this_port_name, move |_, bytes, _| callback(bytes), ())?);
+                    return Ok(())
+                }
+            }
+        }
+
+        Err(PortNotFoundError)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close();
+        }
+    }
+}
+
+const CC_SUSTAIN: u8 = 64;
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_ALL_NOTES_OFF: u8 = 123;
+const PITCH_BEND_CENTER: u16 = 0x2000;
+
 lazy_static! {
     pub static ref GLOBAL_MIDI_CONNECTOR: Mutex<MidiConnector> = Mutex::new(
         MidiConnector::new("GLOBAL_MIDI_CONNECTOR#1".to_string())
@@ -119,12 +238,27 @@ lazy_static! {
     pub static ref GLOBAL_CTL_CONNECTOR: Mutex<MidiConnector> = Mutex::new(
         MidiConnector::new("GLOBAL_CTL_CONNECTOR#1".to_string())
     );
+    pub static ref GLOBAL_CTL_INPUT_CONNECTOR: Mutex<MidiInputConnector> = Mutex::new(
+        MidiInputConnector::new("GLOBAL_CTL_INPUT_CONNECTOR#1".to_string())
+    );
+    // 客户端消息中channel为0时使用的通道（1~16）
+    static ref DEFAULT_CHANNEL: Mutex<i8> = Mutex::new(1);
+}
+
+pub fn set_default_channel(channel: i8) {
+    *DEFAULT_CHANNEL.lock().unwrap() = channel;
+}
+
+/// 把客户端消息中的channel 0替换为默认通道，其它值保持不变
+pub fn resolve_channel(channel: i8) -> i8 {
+    if channel == 0 { *DEFAULT_CHANNEL.lock().unwrap() } else { channel }
 }
 
 #[derive(Debug)]
 pub enum MidiConnectorError {
     InitError(InitError),
     ConnectError(ConnectError<MidiOutput>),
+    InputConnectError(ConnectError<MidiInput>),
     PortNotFoundError
 }
 impl From<InitError> for MidiConnectorError {
@@ -139,6 +273,12 @@ impl From<ConnectError<MidiOutput>> for MidiConnectorError {
     }
 }
 
+impl From<ConnectError<MidiInput>> for MidiConnectorError {
+    fn from(value: ConnectError<MidiInput>) -> Self {
+        MidiConnectorError::InputConnectError(value)
+    }
+}
+
 // ============== TEST ===============
 #[cfg(test)]
 mod midi_connect_test {
@@ -150,6 +290,21 @@ mod midi_connect_test {
         assert_eq!(conn.name, "TestConnector");
     }
 
+    #[test]
+    fn test_track_active_notes() {
+        let mut conn = MidiConnector::new("TestConnector".to_string());
+        conn.track_note(0, 60, true);
+        conn.track_note(0, 64, true);
+        conn.track_note(9, 36, true);
+        conn.track_note(0, 60, false);
+        conn.track_note(16, 60, true);
+        assert_eq!(conn.active_notes(0), vec![64]);
+        assert_eq!(conn.active_notes(9), vec![36]);
+        conn.panic();
+        assert!(conn.active_notes(0).is_empty());
+        assert!(conn.active_notes(16).is_empty());
+    }
+
     #[test]
     fn test_port_list() {
         let mut conn = MidiConnector::new("TestConnector".to_string());
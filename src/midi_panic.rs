@@ -0,0 +1,15 @@
+use crate::arp_handler::GLOBAL_ARP_HANDLER;
+use crate::chord_handler::GLOBAL_CHORD_HANDLER;
+use crate::client_registry::GLOBAL_CLIENT_REGISTRY;
+use crate::midi_connect::GLOBAL_MIDI_CONNECTOR;
+
+/// MIDI panic，停止所有客户端的琶音器与和弦，并让乐器端口上的所有声音停下来
+/// 控制端口不做panic，MCU上的pitch bend是推子，复位它会把DAW里的推子拉到中间
+pub fn midi_panic() {
+    log::warn!("MIDI panic!");
+    // 所有识别符都以空字符串开头
+    GLOBAL_ARP_HANDLER.stop_tasks_with_prefix("");
+    GLOBAL_CHORD_HANDLER.stop_tasks_with_prefix("");
+    GLOBAL_MIDI_CONNECTOR.lock().unwrap().panic();
+    GLOBAL_CLIENT_REGISTRY.lock().unwrap().clear_activity();
+}
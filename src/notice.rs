@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+use crate::message::Message;
+use crate::permission::Role;
+
+/// 服务端无法执行客户端的请求时，通过Notice Message告知客户端原因
+/// 客户端可以据此把当前DAW不支持的操作置灰
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Notice {
+    // 当前DAW配置不支持该操作，比如Ableton Live没有SAVE
+    UnsupportedOperation(i8),
+    // 操作码超出范围
+    InvalidOperation(i8),
+    // 轨道编号超出范围
+    InvalidTrack(i8),
+    InvalidTrackState(i8),
+    InvalidChordType(i8),
+    InvalidChordLevel(i8),
+    InvalidVPot(i8),
+    InvalidVPotState(i8),
+    // DAW编号或DAW配置名不存在
+    InvalidDaw(i8),
+    UnknownDawProfile(String),
+    // 客户端的角色不允许发送该消息，值是至少需要的角色
+    PermissionDenied(Role),
+}
+
+impl Notice {
+    pub fn code(&self) -> i8 {
+        match self {
+            Notice::UnsupportedOperation(_) => NOTICE_UNSUPPORTED_OPERATION,
+            Notice::InvalidOperation(_) => NOTICE_INVALID_OPERATION,
+            Notice::InvalidTrack(_) => NOTICE_INVALID_TRACK,
+            Notice::InvalidTrackState(_) => NOTICE_INVALID_TRACK_STATE,
+            Notice::InvalidChordType(_) => NOTICE_INVALID_CHORD_TYPE,
+            Notice::InvalidChordLevel(_) => NOTICE_INVALID_CHORD_LEVEL,
+            Notice::InvalidVPot(_) => NOTICE_INVALID_VPOT,
+            Notice::InvalidVPotState(_) => NOTICE_INVALID_VPOT_STATE,
+            Notice::InvalidDaw(_) => NOTICE_INVALID_DAW,
+            Notice::UnknownDawProfile(_) => NOTICE_UNKNOWN_DAW_PROFILE,
+            Notice::PermissionDenied(_) => NOTICE_PERMISSION_DENIED,
+        }
+    }
+
+    /// 引起该通知的字段值，比如不支持的操作码
+    pub fn value(&self) -> i8 {
+        match self {
+            Notice::UnsupportedOperation(value) | Notice::InvalidOperation(value)
+            | Notice::InvalidTrack(value) | Notice::InvalidTrackState(value)
+            | Notice::InvalidChordType(value) | Notice::InvalidChordLevel(value)
+            | Notice::InvalidVPot(value) | Notice::InvalidVPotState(value)
+            | Notice::InvalidDaw(value) => *value,
+            Notice::UnknownDawProfile(_) => -1,
+            Notice::PermissionDenied(role) => role.code(),
+        }
+    }
+
+    /// op是引起该通知的请求的消息类型
+    pub fn to_message(&self, op: i8) -> Message {
+        Message::NoticeMessage {
+            code: self.code(),
+            op,
+            value: self.value(),
+            text: self.to_string()
+        }
+    }
+}
+
+impl Display for Notice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Notice::UnsupportedOperation(operation) => write!(f, "operation {} is unsupported by current daw", operation),
+            Notice::InvalidOperation(operation) => write!(f, "operation code {} is out of bounds", operation),
+            Notice::InvalidTrack(nth) => write!(f, "track {} is invaild", nth),
+            Notice::InvalidTrackState(state) => write!(f, "track state {} is invaild", state),
+            Notice::InvalidChordType(chord_type) => write!(f, "chord type {} is invaild", chord_type),
+            Notice::InvalidChordLevel(chord_level) => write!(f, "chord level {} is invaild", chord_level),
+            Notice::InvalidVPot(nth) => write!(f, "vpot {} is invaild", nth),
+            Notice::InvalidVPotState(state) => write!(f, "vpot state {} is invaild", state),
+            Notice::InvalidDaw(daw) => write!(f, "daw code {} is invaild", daw),
+            Notice::UnknownDawProfile(name) => write!(f, "daw profile {} does not exist", name),
+            Notice::PermissionDenied(role) => write!(f, "role {} is required", role),
+        }
+    }
+}
+
+pub const NOTICE_UNSUPPORTED_OPERATION: i8 = 1;
+pub const NOTICE_INVALID_OPERATION: i8 = 2;
+pub const NOTICE_INVALID_TRACK: i8 = 3;
+pub const NOTICE_INVALID_TRACK_STATE: i8 = 4;
+pub const NOTICE_INVALID_CHORD_TYPE: i8 = 5;
+pub const NOTICE_INVALID_CHORD_LEVEL: i8 = 6;
+pub const NOTICE_INVALID_VPOT: i8 = 7;
+pub const NOTICE_INVALID_VPOT_STATE: i8 = 8;
+pub const NOTICE_INVALID_DAW: i8 = 9;
+pub const NOTICE_UNKNOWN_DAW_PROFILE: i8 = 10;
+pub const NOTICE_PERMISSION_DENIED: i8 = 11;
+
+#[cfg(test)]
+mod notice_test {
+    use crate::constants::CONTROL_OP;
+    use crate::message::Message::NoticeMessage;
+    use crate::notice::{Notice, NOTICE_UNKNOWN_DAW_PROFILE, NOTICE_UNSUPPORTED_OPERATION};
+
+    #[test]
+    fn test_to_message() {
+        if let NoticeMessage { code, op, value, text } = Notice::UnsupportedOperation(6).to_message(CONTROL_OP) {
+            assert_eq!(code, NOTICE_UNSUPPORTED_OPERATION);
+            assert_eq!(op, CONTROL_OP);
+            assert_eq!(value, 6);
+            assert_eq!(text, "operation 6 is unsupported by current daw");
+        } else {
+            panic!("not a notice message");
+        }
+    }
+
+    #[test]
+    fn test_unknown_profile_has_no_value() {
+        let notice = Notice::UnknownDawProfile("bitwig".into());
+        assert_eq!(notice.code(), NOTICE_UNKNOWN_DAW_PROFILE);
+        assert_eq!(notice.value(), -1);
+    }
+}
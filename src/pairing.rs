@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::message::Message;
+use crate::public::write_private_file;
+
+/// 配对，开启后客户端必须在握手时出示服务端显示的PIN，或者之前配对时拿到的密钥
+/// 用PIN配对成功的客户端会收到一个密钥，之后重连时出示密钥即可，不需要再输入PIN
+/// 没有通过的连接在任何消息被处理之前就会被断开
+pub struct Pairing {
+    pin: String,
+    // 每个IP连续出示错误token的次数以及下一次允许尝试的时间，避免PIN被暴力猜出
+    // 只锁定出错的IP，而不是更换PIN，这样别人故意输错也不会让二维码失效
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    // 密钥 -> 已配对的客户端
+    paired: HashMap<String, PairedClient>,
+    // 保存已配对客户端的文件，为None时只保存在内存中
+    store: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairedClient {
+    pub name: String,
+    // 配对时间，unix时间戳（秒）
+    pub paired_at: u64,
+}
+
+#[derive(Debug)]
+pub enum PairingError {
+    // 连接的第一条消息不是握手
+    NotHandShake,
+    // 该IP输错的次数太多，还在锁定中
+    LockedOut(Duration),
+    // 握手时没有出示PIN或者密钥
+    MissingToken,
+    InvalidToken,
+    IOError(PathBuf, std::io::Error),
+    ParseError(PathBuf, String),
+}
+
+impl Display for PairingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PairingError::NotHandShake => write!(f, "the first message is not a handshake"),
+            PairingError::LockedOut(remaining) => write!(f, "too many wrong tokens, locked out for {:?}", remaining),
+            PairingError::MissingToken => write!(f, "no pairing token is presented"),
+            PairingError::InvalidToken => write!(f, "the pairing token is invalid"),
+            PairingError::IOError(path, e) => write!(f, "cannot access paired clients {:?}: {}", path, e),
+            PairingError::ParseError(path, e) => write!(f, "cannot parse paired clients {:?}: {}", path, e),
+        }
+    }
+}
+
+impl Pairing {
+    /// 从store中加载已配对的客户端，文件不存在时视为没有已配对的客户端
+    pub fn new(store: Option<PathBuf>) -> Result<Pairing, PairingError> {
+        let paired = match &store {
+            Some(path) if path.exists() => load_paired_clients(path)?,
+            _ => HashMap::new()
+        };
+        Ok(Pairing { pin: generate_pin(), failures: HashMap::new(), paired, store })
+    }
+
+    pub fn pin(&self) -> &str {
+        &self.pin
+    }
+
+    /// token是密钥时返回Ok(None)；是PIN时记住这个客户端，返回新生成的密钥
+    /// 每次出错后该IP都要等待一段时间才能再次尝试，等待时间每次翻倍，锁定期间不检查token
+    pub fn authorize(&mut self, name: &str, token: &str, ip: IpAddr) -> Result<Option<String>, PairingError> {
+        let now = Instant::now();
+        if let Some((_, locked_until)) = self.failures.get(&ip) {
+            if *locked_until > now {
+                return Err(PairingError::LockedOut(*locked_until - now));
+            }
+        }
+        let is_key = self.paired.keys().fold(false, |found, key| found | constant_time_eq(key.as_bytes(), token.as_bytes()));
+        if is_key {
+            self.failures.remove(&ip);
+            return Ok(None);
+        }
+        if !constant_time_eq(self.pin.as_bytes(), token.as_bytes()) {
+            let failures = self.failures.get(&ip).map(|(failures, _)| failures + 1).unwrap_or(1);
+            let lockout = MIN_LOCKOUT.saturating_mul(1 << (failures - 1).min(16)).min(MAX_LOCKOUT);
+            self.failures.insert(ip, (failures, now + lockout));
+            log::warn!("{} wrong pairing tokens from {}, locked out for {:?}", failures, ip, lockout);
+            return Err(PairingError::InvalidToken);
+        }
+        self.failures.remove(&ip);
+        let key = generate_key();
+        let paired_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.paired.insert(key.clone(), PairedClient { name: name.to_string(), paired_at });
+        Ok(Some(key))
+    }
+
+    /// 需要写入store的内容，没有store时为None
+    /// 写文件在锁外面做，避免在异步任务中持有锁做阻塞IO
+    fn store_content(&self) -> Result<Option<(PathBuf, String)>, PairingError> {
+        let path = match &self.store {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+        let content = serde_json::to_string_pretty(&self.paired)
+            .map_err(|e| PairingError::ParseError(path.clone(), e.to_string()))?;
+        Ok(Some((path.clone(), content)))
+    }
+}
+
+fn write_store(path: &Path, content: &str) -> Result<(), PairingError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| PairingError::IOError(path.to_path_buf(), e))?;
+    }
+    // 密钥相当于密码，只有当前用户可以读取
+    write_private_file(path, content.as_bytes()).map_err(|e| PairingError::IOError(path.to_path_buf(), e))
+}
+
+/// 比较时间与内容无关，避免通过响应时间逐位猜出PIN或密钥
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+lazy_static! {
+    // 为None时没有开启配对，任何客户端都可以连接
+    static ref GLOBAL_PAIRING: Mutex<Option<Pairing>> = Mutex::new(None);
+}
+
+/// 开启配对，返回本次运行的PIN
+pub fn enable_pairing(store: Option<PathBuf>) -> Result<String, PairingError> {
+    let pairing = Pairing::new(store)?;
+    let pin = pairing.pin().to_string();
+    *GLOBAL_PAIRING.lock().unwrap() = Some(pairing);
+    Ok(pin)
+}
+
+/// 当前的PIN，没有开启配对时为None
+pub fn pairing_pin() -> Option<String> {
+    GLOBAL_PAIRING.lock().unwrap().as_ref().map(|pairing| pairing.pin().to_string())
+}
+
+/// 检查连接的第一条消息，没有开启配对时总是通过
+/// 用PIN配对成功时返回新的密钥，需要通过Pair Message告诉客户端
+pub async fn authorize(msg: &Message, ip: IpAddr) -> Result<Option<String>, PairingError> {
+    let (key, store) = {
+        let mut pairing = GLOBAL_PAIRING.lock().unwrap();
+        let pairing = match pairing.as_mut() {
+            Some(pairing) => pairing,
+            None => return Ok(None)
+        };
+        let key = match msg {
+            Message::HandShake { name, token: Some(token), .. } => pairing.authorize(name, token, ip)?,
+            Message::HandShake { .. } => return Err(PairingError::MissingToken),
+            _ => return Err(PairingError::NotHandShake)
+        };
+        let store = if key.is_some() { pairing.store_content()? } else { None };
+        (key, store)
+    };
+    // 保存失败时密钥在本次运行中仍然有效，只是重启后需要重新配对
+    if let Some((path, content)) = store {
+        let saved = tokio::task::spawn_blocking(move || write_store(&path, &content)).await;
+        if let Ok(Err(e)) = saved {
+            log::warn!("Cannot save paired clients: {}", e);
+        }
+    }
+    Ok(key)
+}
+
+pub fn is_pairing_enabled() -> bool {
+    GLOBAL_PAIRING.lock().unwrap().is_some()
+}
+
+pub fn default_paired_clients_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("vpad").join("paired_clients.json"))
+}
+
+fn load_paired_clients(path: &Path) -> Result<HashMap<String, PairedClient>, PairingError> {
+    let content = fs::read_to_string(path).map_err(|e| PairingError::IOError(path.to_path_buf(), e))?;
+    serde_json::from_str(&content).map_err(|e| PairingError::ParseError(path.to_path_buf(), e.to_string()))
+}
+
+fn generate_pin() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+const MIN_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_secs(300);
+
+#[cfg(test)]
+mod pairing_test {
+    use std::env;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Instant;
+    use crate::pairing::{constant_time_eq, Pairing, PairingError, write_store};
+
+    const PHONE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+    const GUEST: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 3));
+
+    #[test]
+    fn test_pair_with_pin_then_key() {
+        let mut pairing = Pairing::new(None).unwrap();
+        let pin = pairing.pin().to_string();
+        assert_eq!(pin.len(), 6);
+        let key = pairing.authorize("phone", &pin, PHONE).unwrap().unwrap();
+        assert_eq!(key.len(), 32);
+        assert!(pairing.authorize("phone", &key, PHONE).unwrap().is_none());
+        assert!(matches!(pairing.authorize("phone", "000000x", PHONE), Err(PairingError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_wrong_tokens_lock_out_the_ip() {
+        let mut pairing = Pairing::new(None).unwrap();
+        let pin = pairing.pin().to_string();
+        assert!(matches!(pairing.authorize("guest", "wrong", GUEST), Err(PairingError::InvalidToken)));
+        // 锁定期间即使出示了正确的PIN也不会被检查
+        assert!(matches!(pairing.authorize("guest", &pin, GUEST), Err(PairingError::LockedOut(_))));
+        // 其它IP不受影响，PIN也没有改变
+        assert!(pairing.authorize("phone", &pin, PHONE).unwrap().is_some());
+
+        // 锁定时间每次翻倍
+        pairing.failures.insert(GUEST, (3, Instant::now()));
+        assert!(pairing.authorize("guest", "wrong", GUEST).is_err());
+        let (failures, locked_until) = pairing.failures[&GUEST];
+        assert_eq!(failures, 4);
+        assert!(locked_until.duration_since(Instant::now()).as_secs() >= 7);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"123456", b"123456"));
+        assert!(!constant_time_eq(b"123456", b"123457"));
+        assert!(!constant_time_eq(b"123456", b"12345"));
+    }
+
+    #[test]
+    fn test_paired_clients_are_stored() {
+        let path = env::temp_dir().join(format!("vpad_paired_{}.json", std::process::id()));
+        let mut pairing = Pairing::new(Some(path.clone())).unwrap();
+        let pin = pairing.pin().to_string();
+        let key = pairing.authorize("phone", &pin, PHONE).unwrap().unwrap();
+        let (store, content) = pairing.store_content().unwrap().unwrap();
+        write_store(&store, &content).unwrap();
+
+        let mut reloaded = Pairing::new(Some(path.clone())).unwrap();
+        assert!(reloaded.authorize("phone", &key, PHONE).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
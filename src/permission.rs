@@ -0,0 +1,113 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::constants::{CONTROL_OP, DAW_SELECT_OP, JOG_OP, TRACK_OP, VPOT_OP};
+use crate::message::Message;
+
+/// 客户端的角色，限制它可以发送的消息，比如排练时客人的手机只能弹奏，不能让DAW停止或者录音
+/// 角色是递增的，高的角色拥有低的角色的所有权限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    // 只能演奏：音符、琶音、和弦、弯音轮、CC，以及panic
+    Notes,
+    // 额外可以控制调音台：推子、静音、独奏、VPot
+    Mixer,
+    // 额外可以控制走带和DAW：Control Message、Jog、选择DAW（包括握手时选择）
+    Full,
+}
+
+impl Role {
+    pub fn code(&self) -> i8 {
+        match self {
+            Role::Notes => 0,
+            Role::Mixer => 1,
+            Role::Full => 2,
+        }
+    }
+
+    pub fn allows(&self, msg: &Message) -> bool {
+        match restriction_of(msg) {
+            Some((_, required)) => *self >= required,
+            None => true
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notes" => Ok(Role::Notes),
+            "mixer" => Ok(Role::Mixer),
+            "full" => Ok(Role::Full),
+            _ => Err(format!("unknown role '{}', expect one of notes, mixer, full", s))
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Notes => write!(f, "notes"),
+            Role::Mixer => write!(f, "mixer"),
+            Role::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// 受限制的消息，返回它的消息类型以及至少需要的角色；没有限制的消息返回None
+pub fn restriction_of(msg: &Message) -> Option<(i8, Role)> {
+    match msg {
+        Message::TrackMessage { .. } => Some((TRACK_OP, Role::Mixer)),
+        Message::VPotMessage { .. } => Some((VPOT_OP, Role::Mixer)),
+        Message::ControlMessage { .. } => Some((CONTROL_OP, Role::Full)),
+        Message::JogMessage { .. } => Some((JOG_OP, Role::Full)),
+        Message::DawSelectMessage { .. } => Some((DAW_SELECT_OP, Role::Full)),
+        _ => None
+    }
+}
+
+lazy_static! {
+    // 新连接的客户端的角色，默认为Full，与没有角色之前的行为一致
+    static ref DEFAULT_ROLE: Mutex<Role> = Mutex::new(Role::Full);
+}
+
+pub fn default_role() -> Role {
+    *DEFAULT_ROLE.lock().unwrap()
+}
+
+pub fn set_default_role(role: Role) {
+    *DEFAULT_ROLE.lock().unwrap() = role;
+}
+
+#[cfg(test)]
+mod permission_test {
+    use crate::message::Message;
+    use crate::permission::Role;
+
+    #[test]
+    fn test_role_allows() {
+        let control = Message::ControlMessage { operation: 1, state: 1, auto_close: 1 };
+        let track = Message::TrackMessage { nth: 1, state: 0, value: 100, fine_value: None };
+        let note = Message::Midi { note: 60, velocity: 100, state: 1, channel: 1 };
+        assert!(Role::Notes.allows(&note));
+        assert!(!Role::Notes.allows(&track));
+        assert!(!Role::Notes.allows(&control));
+        assert!(Role::Mixer.allows(&track));
+        assert!(!Role::Mixer.allows(&control));
+        assert!(Role::Full.allows(&control));
+        let daw_select = Message::DawSelectMessage { daw: 6, name: None };
+        assert!(!Role::Mixer.allows(&daw_select));
+        assert!(Role::Full.allows(&daw_select));
+        assert!(Role::Notes.allows(&Message::PanicMessage));
+    }
+
+    #[test]
+    fn test_parse_role() {
+        assert_eq!("mixer".parse::<Role>(), Ok(Role::Mixer));
+        assert_eq!(Role::Notes.to_string().parse::<Role>(), Ok(Role::Notes));
+        assert!("admin".parse::<Role>().is_err());
+    }
+}
@@ -1,6 +1,47 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
 use crate::midi_connect::{MidiConnector, Result};
 
 
 pub fn fetch_midi_ports() -> Result<Vec<String>> {
     MidiConnector::port_list()
 }
+
+/// 写入只有当前用户可以读写的文件（unix下为0600），用于私钥、配对密钥这类文件
+/// 文件已经存在时也会把权限改为0600
+pub fn write_private_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(content)
+    }
+    #[cfg(not(unix))]
+    {
+        options.open(path)?.write_all(content)
+    }
+}
+
+#[cfg(test)]
+mod public_test {
+    use std::env;
+    use std::fs;
+    use crate::public::write_private_file;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_file_is_only_readable_by_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("vpad_private_{}", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        write_private_file(&path, b"secret").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+/// 音阶，开启后客户端弹奏的音符被对齐到音阶内，不会弹出调外音
+/// 琶音与和弦只对齐根音，由根音生成的其它音符不受影响
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    // 主音的音级，0代表C
+    root: u8,
+    mode: ScaleMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    Chromatic,
+    Major,
+    Minor,
+    HarmonicMinor,
+    Dorian,
+    Mixolydian,
+    Pentatonic,
+    MinorPentatonic,
+}
+
+impl ScaleMode {
+    const ALL: [ScaleMode; 8] = [
+        ScaleMode::Chromatic, ScaleMode::Major, ScaleMode::Minor, ScaleMode::HarmonicMinor,
+        ScaleMode::Dorian, ScaleMode::Mixolydian, ScaleMode::Pentatonic, ScaleMode::MinorPentatonic,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Chromatic => "chromatic",
+            ScaleMode::Major => "major",
+            ScaleMode::Minor => "minor",
+            ScaleMode::HarmonicMinor => "harmonic-minor",
+            ScaleMode::Dorian => "dorian",
+            ScaleMode::Mixolydian => "mixolydian",
+            ScaleMode::Pentatonic => "pentatonic",
+            ScaleMode::MinorPentatonic => "minor-pentatonic",
+        }
+    }
+
+    // 音阶内的音与主音相差的半音数
+    fn intervals(&self) -> &'static [u8] {
+        match self {
+            ScaleMode::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            ScaleMode::Major => &[0, 2, 4, 5, 7, 9, 11],
+            ScaleMode::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            ScaleMode::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleMode::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            ScaleMode::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            ScaleMode::Pentatonic => &[0, 2, 4, 7, 9],
+            ScaleMode::MinorPentatonic => &[0, 3, 5, 7, 10],
+        }
+    }
+}
+
+impl Scale {
+    pub const CHROMATIC: Scale = Scale { root: 0, mode: ScaleMode::Chromatic };
+
+    fn contains(&self, note: i16) -> bool {
+        let degree = (note - self.root as i16).rem_euclid(12) as u8;
+        self.mode.intervals().contains(&degree)
+    }
+
+    /// 调外音向下对齐到最近的调内音，最低的几个音下面没有调内音时向上对齐
+    /// 同一个音总是对齐到同一个音，所以按下与松开的音符是一致的
+    pub fn snap(&self, note: i8) -> i8 {
+        let note = note as i16;
+        let below = (0..12).map(|offset| note - offset).find(|n| *n >= 0 && self.contains(*n));
+        let above = || (1..12).map(|offset| note + offset).find(|n| *n <= 127 && self.contains(*n));
+        below.or_else(above).unwrap_or(note) as i8
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    /// 格式为`<主音> <调式>`，比如`C major`、`F# minor`、`Bb dorian`，或者`chromatic`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("unknown scale '{}', expect chromatic or '<root> <mode>' like 'C major', modes are: {}",
+                               s, ScaleMode::ALL.iter().skip(1).map(|mode| mode.name()).collect::<Vec<&str>>().join(", "));
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts[..] {
+            [mode] if mode.eq_ignore_ascii_case(ScaleMode::Chromatic.name()) => Ok(Scale::CHROMATIC),
+            [root, mode] => {
+                let root = parse_root(root).ok_or_else(error)?;
+                let mode = ScaleMode::ALL.into_iter().find(|m| m.name().eq_ignore_ascii_case(mode)).ok_or_else(error)?;
+                Ok(Scale { root, mode })
+            }
+            _ => Err(error())
+        }
+    }
+}
+
+impl Display for Scale {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.mode {
+            ScaleMode::Chromatic => write!(f, "{}", self.mode.name()),
+            _ => write!(f, "{} {}", ROOT_NAMES[self.root as usize], self.mode.name())
+        }
+    }
+}
+
+// 音名，可以带一个#或者b
+fn parse_root(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let base: i8 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+        _ => return None
+    };
+    let accidental: i8 = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return None
+    };
+    Some((base + accidental).rem_euclid(12) as u8)
+}
+
+const ROOT_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+lazy_static! {
+    // 默认为chromatic，不做对齐，与没有音阶之前的行为一致
+    static ref SCALE: Mutex<Scale> = Mutex::new(Scale::CHROMATIC);
+}
+
+pub fn set_scale(scale: Scale) {
+    *SCALE.lock().unwrap() = scale;
+}
+
+pub fn snap_to_scale(note: i8) -> i8 {
+    SCALE.lock().unwrap().snap(note)
+}
+
+#[cfg(test)]
+mod scale_test {
+    use crate::scale::Scale;
+
+    #[test]
+    fn test_parse_scale() {
+        assert_eq!("chromatic".parse::<Scale>(), Ok(Scale::CHROMATIC));
+        assert_eq!("Bb dorian".parse::<Scale>().unwrap().to_string(), "A# dorian");
+        assert_eq!("f# Minor".parse::<Scale>().unwrap().to_string(), "F# minor");
+        assert!("H major".parse::<Scale>().is_err());
+        assert!("C lydian".parse::<Scale>().is_err());
+        assert!("C".parse::<Scale>().is_err());
+    }
+
+    #[test]
+    fn test_snap() {
+        let c_major: Scale = "C major".parse().unwrap();
+        // C4 = 60
+        assert_eq!(c_major.snap(60), 60);
+        assert_eq!(c_major.snap(61), 60);
+        assert_eq!(c_major.snap(66), 65);
+        let a_minor_pentatonic: Scale = "A minor-pentatonic".parse().unwrap();
+        assert_eq!(a_minor_pentatonic.snap(59), 57);
+        // C不在D大调内，下面没有调内音时向上对齐到C#
+        let d_major: Scale = "D major".parse().unwrap();
+        assert_eq!(d_major.snap(0), 1);
+        assert_eq!(Scale::CHROMATIC.snap(61), 61);
+    }
+}
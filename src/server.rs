@@ -1,102 +1,434 @@
 use std::fmt::{Debug};
-use std::io::{Error};
+use std::io::{Error, ErrorKind};
 use std::net::{IpAddr, SocketAddr};
 use std::result;
-use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{StreamExt, SinkExt};
+use std::time::{Duration, Instant};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use tokio;
-use tokio::net::{TcpSocket, TcpStream};
-use tokio::sync::{mpsc};
-use tokio::sync::mpsc::error::SendError;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use lazy_static::lazy_static;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
-use crate::message::Message;
-use crate::message_codec::MessageCodec;
-
-pub type Result = result::Result<(), VPadServerError>;
-
-type MPSCChan = (mpsc::Sender<()>, mpsc::Receiver<()>);
+use crate::client_registry::GLOBAL_CLIENT_REGISTRY;
+use crate::control_handler::default_daw;
+use crate::discovery::serve_discovery;
+use crate::message::{Message, release_client};
+use crate::midi_connect::{GLOBAL_CTL_CONNECTOR, GLOBAL_CTL_INPUT_CONNECTOR, GLOBAL_MIDI_CONNECTOR};
+use crate::midi_panic::midi_panic;
+use crate::mdns::MdnsAdvertiser;
+use crate::pairing::{authorize, is_pairing_enabled};
+use crate::tls::tls_acceptor;
+use crate::message_codec::{MessageCodec, MessageCodecError};
+use crate::udp_server::serve_udp;
+use crate::websocket::serve_websocket;
 
 pub struct VPadServer {
-    pub ipaddr: IpAddr,
+    // 监听地址，至少有一个，TCP、UDP通道和WebSocket都在所有地址上监听
+    pub addrs: Vec<IpAddr>,
+    // 期望的端口，listen之后是实际使用的端口
     pub port: u16,
-    close_channel: MPSCChan,
+    pub backlog: u32,
+    // IPv6地址是否同时接受IPv4连接，否则IPv6地址只接受IPv6连接，可以与0.0.0.0同时监听
+    pub dual_stack: bool,
+    // WebSocket监听端口，为None时不开启WebSocket
+    pub ws_port: Option<u16>,
+    // UDP广播发现的端口，为None时不回复探测包
+    pub discovery_port: Option<u16>,
+    listeners: Vec<TcpListener>,
+    // 与listeners一一对应，使用相同的地址和端口
+    udp_sockets: Vec<std::net::UdpSocket>,
 }
 
 impl VPadServer {
     pub fn bind(ipaddr:IpAddr, port: u16)  -> VPadServer {
         VPadServer {
-            ipaddr, port,
-            close_channel: mpsc::channel(1)
+            addrs: vec![ipaddr], port,
+            backlog: DEFAULT_BACKLOG,
+            dual_stack: false,
+            ws_port: None,
+            discovery_port: None,
+            listeners: Vec::new(),
+            udp_sockets: Vec::new(),
+        }
+    }
+
+    /// 在另一个地址上同时监听，比如同时监听0.0.0.0和::
+    pub fn with_address(mut self, ipaddr: IpAddr) -> VPadServer {
+        if !self.addrs.contains(&ipaddr) {
+            self.addrs.push(ipaddr);
         }
+        self
     }
 
-    pub async fn start(self) -> Result {
-        let (_tx, mut rx) = self.close_channel;
+    pub fn with_backlog(mut self, backlog: u32) -> VPadServer {
+        self.backlog = backlog;
+        self
+    }
 
-        let socket = TcpSocket::new_v4()?;
-        socket.set_reuseaddr(true)?;
-        socket.bind(SocketAddr::new(self.ipaddr, self.port))?;
+    pub fn with_dual_stack(mut self, dual_stack: bool) -> VPadServer {
+        self.dual_stack = dual_stack;
+        self
+    }
 
-        let listener = socket.listen(1024)?;
-        loop {
-            // 在两个异步任务上轮询，第一个完成的任务的代码块将被执行，另一个代码块将被放弃
-            tokio::select! {
-                Ok((socket, addr)) = listener.accept() => {
-                    tokio::spawn(process_socket(socket, addr));
-                },
-                _ = rx.recv() => {
-                    break;
+    /// 在另一个端口上同时接受WebSocket连接，供浏览器中的客户端使用
+    pub fn with_websocket(mut self, ws_port: u16) -> VPadServer {
+        self.ws_port = Some(ws_port);
+        self
+    }
+
+    /// 回复局域网内客户端广播的探测包，用于mDNS被屏蔽的网络
+    pub fn with_discovery(mut self, discovery_port: u16) -> VPadServer {
+        self.discovery_port = Some(discovery_port);
+        self
+    }
+
+    /// 在所有地址上监听同一个端口（TCP与UDP），端口被占用时依次尝试后面的MAX_PORT_FALLBACK个端口
+    /// 返回实际使用的端口，二维码等需要告诉客户端端口的地方应当在start之前调用它
+    pub fn listen(&mut self) -> result::Result<u16, VPadServerError> {
+        if !self.listeners.is_empty() {
+            return Ok(self.port);
+        }
+        let last_port = self.port.saturating_add(MAX_PORT_FALLBACK);
+        for port in self.port..=last_port {
+            // 端口为0时操作系统分配的TCP端口的UDP可能被占用，这时重新分配而不是尝试1、2等端口
+            let port = if self.port == 0 { 0 } else { port };
+            match self.bind_all(port) {
+                Ok((listeners, udp_sockets)) => {
+                    self.port = listeners[0].local_addr()?.port();
+                    self.listeners = listeners;
+                    self.udp_sockets = udp_sockets;
+                    for listener in &self.listeners {
+                        log::info!("VPadServer is listening on {:?}", listener.local_addr()?);
+                    }
+                    return Ok(self.port);
+                }
+                Err(e) if e.kind() == ErrorKind::AddrInUse && port < last_port => {
+                    log::warn!("Port {} is in use, trying {}", port, port + 1);
                 }
+                Err(e) => return Err(e.into())
             }
         }
-        Ok(())
+        unreachable!()
     }
 
-    #[allow(dead_code)]
-    pub async fn close(self) -> Result {
-        self.close_channel.0.send(()).await?;
-        Ok(())
+    /// 端口为0时由操作系统分配，UDP以及其它地址使用第一个地址分配到的TCP端口
+    fn bind_all(&self, port: u16) -> std::io::Result<(Vec<TcpListener>, Vec<std::net::UdpSocket>)> {
+        let mut listeners: Vec<TcpListener> = Vec::new();
+        let mut udp_sockets = Vec::new();
+        for ipaddr in &self.addrs {
+            let port = match listeners.first() {
+                Some(first) => first.local_addr()?.port(),
+                None => port
+            };
+            let listener = bind_listener(SocketAddr::new(*ipaddr, port), self.backlog, self.dual_stack)?;
+            udp_sockets.push(bind_udp_socket(listener.local_addr()?, self.dual_stack)?);
+            listeners.push(listener);
+        }
+        Ok((listeners, udp_sockets))
     }
 
+    /// 开始接受连接并立即返回，通过返回的ServerHandle关闭服务
+    pub async fn start(mut self) -> result::Result<ServerHandle, VPadServerError> {
+        let port = self.listen()?;
+
+        let mut tasks: Vec<JoinHandle<()>> = self.udp_sockets.into_iter()
+            .map(|socket| tokio::spawn(serve_udp(socket)))
+            .collect();
+        if let Some(ws_port) = self.ws_port {
+            // WebSocket端口被占用时不影响TCP连接，所以只记录错误
+            for ipaddr in &self.addrs {
+                match bind_listener(SocketAddr::new(*ipaddr, ws_port), self.backlog, self.dual_stack) {
+                    Ok(listener) => tasks.push(tokio::spawn(serve_websocket(listener))),
+                    Err(e) => log::error!("Cannot bind websocket port {} on {}: {:?}", ws_port, ipaddr, e)
+                }
+            }
+        }
+        if let Some(discovery_port) = self.discovery_port {
+            tasks.push(tokio::spawn(serve_discovery(discovery_port, port, self.ws_port)));
+        }
+        // mDNS广播失败时客户端仍然可以扫码连接，所以只记录警告
+        let mdns = MdnsAdvertiser::start(port, self.ws_port)
+            .map_err(|e| log::warn!("Cannot advertise over mDNS: {:?}", e))
+            .ok();
+        tasks.extend(self.listeners.into_iter().map(|listener| tokio::spawn(accept_connections(listener))));
+        Ok(ServerHandle { port, tasks, mdns })
+    }
 }
 
-pub struct VPadMessageContext {
-    pub addr: SocketAddr
+/// 正在运行的VPadServer
+pub struct ServerHandle {
+    pub port: u16,
+    // 接受TCP连接、WebSocket连接、UDP数据报以及广播发现的任务
+    tasks: Vec<JoinHandle<()>>,
+    mdns: Option<MdnsAdvertiser>,
+}
+
+impl ServerHandle {
+    /// 优雅地关闭服务：
+    ///     1. 停止接受新的连接，停止mDNS广播
+    ///     2. 通知所有客户端服务端即将关闭，写任务发完队列中的消息后断开连接
+    ///     3. 等待所有客户端断开（最多SHUTDOWN_DRAIN_TIMEOUT），断开时会松开它们按下的音符
+    ///     4. 踢掉没有断开的客户端，并等待它们的连接任务注销（最多SHUTDOWN_KICK_TIMEOUT）
+    ///     5. MIDI panic，然后关闭所有MIDI端口，此时已经没有读任务会再发出音符
+    pub async fn shutdown(self) {
+        log::info!("Shutting down VPadServer");
+        for task in &self.tasks {
+            task.abort();
+        }
+        drop(self.mdns);
+
+        let _ = SHUTDOWN_SIGNAL.send(true);
+        if !wait_for_clients_gone(SHUTDOWN_DRAIN_TIMEOUT).await {
+            let remaining = GLOBAL_CLIENT_REGISTRY.lock().unwrap().clients();
+            for client in remaining {
+                log::warn!("{:?} did not disconnect in time, kicking it", client.addr);
+                GLOBAL_CLIENT_REGISTRY.lock().unwrap().kick(client.id);
+            }
+            if !wait_for_clients_gone(SHUTDOWN_KICK_TIMEOUT).await {
+                log::warn!("Some clients are still connected after being kicked");
+            }
+        }
+
+        midi_panic();
+        GLOBAL_MIDI_CONNECTOR.lock().unwrap().close();
+        GLOBAL_CTL_CONNECTOR.lock().unwrap().close();
+        GLOBAL_CTL_INPUT_CONNECTOR.lock().unwrap().close();
+        log::info!("VPadServer is shut down");
+    }
+}
+
+/// 等待注册表变空，超时时返回false
+/// 连接任务在读写任务真正结束之后才注销，所以注册表为空时不会再有客户端的消息被处理
+async fn wait_for_clients_gone(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !GLOBAL_CLIENT_REGISTRY.lock().unwrap().clients().is_empty() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+    true
+}
+
+/// 等待SIGINT（Ctrl-C）或SIGTERM
+#[cfg(unix)]
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("Cannot listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn accept_connections(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                tokio::spawn(process_socket(socket, addr));
+            }
+            Err(e) => log::info!("Accept connection error: {:?}", e)
+        }
+    }
+}
+
+/// IPv6地址按dual_stack决定是否同时接受IPv4连接
+/// Windows上SO_REUSEADDR允许抢占已经被占用的端口，会让端口回退失效，所以只在unix上开启
+fn bind_listener(addr: SocketAddr, backlog: u32, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
 }
 
-type MessageFramedStream = SplitStream<Framed<TcpStream, MessageCodec>>;
-type MessageFramedSink = SplitSink<Framed<TcpStream, MessageCodec>, Message>;
+fn bind_udp_socket(addr: SocketAddr, dual_stack: bool) -> std::io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+lazy_static! {
+    // 服务端主动推送给所有客户端的消息，每个连接的写任务都会订阅它
+    static ref CLIENT_BROADCAST: broadcast::Sender<Message> = broadcast::channel(64).0;
+    // 服务端关闭时变为true，每个连接的写任务都会订阅它
+    static ref SHUTDOWN_SIGNAL: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// 向所有已连接的客户端推送消息，没有客户端时消息被丢弃
+pub fn broadcast_to_clients(msg: Message) {
+    let _ = CLIENT_BROADCAST.send(msg);
+}
+
+pub struct VPadMessageContext {
+    pub addr: SocketAddr,
+    // 该客户端在注册表中的id
+    pub client_id: u64,
+    // 该客户端使用的DAW配置名，每个客户端可以不同
+    pub daw: String,
+    // 客户端是否支持心跳，回复过Pong或者发送过Ping的客户端才会被空闲超时断开
+    pub heartbeat: bool
+}
 
 #[allow(unused_must_use)]
 async fn process_socket(socket: TcpStream, addr: SocketAddr) {
     log::info!("Got a new connection from: {:?}", addr);
+    prepare_socket(&socket, addr);
 
-    let framed = Framed::new(socket, MessageCodec{});
+    match tls_acceptor() {
+        Some(acceptor) => match acceptor.accept(socket).await {
+            Ok(stream) => serve_framed(stream, addr).await,
+            Err(e) => log::info!("TLS handshake with {:?} failed: {:?}", addr, e)
+        },
+        None => serve_framed(socket, addr).await
+    }
+}
+
+/// 明文和TLS连接上的消息帧完全相同
+async fn serve_framed<S>(stream: S, addr: SocketAddr)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let framed = Framed::new(stream, MessageCodec{});
     let (frame_writer, frame_reader) =
         framed.split::<Message>();
+    serve_client(frame_reader, frame_writer, addr).await;
+}
+
+/// 关闭Nagle算法，音符消息很小，不能等着和后面的消息合并发送；并开启TCP keepalive
+pub fn prepare_socket(socket: &TcpStream, addr: SocketAddr) {
+    if let Err(e) = socket.set_nodelay(true) {
+        log::warn!("Cannot set tcp nodelay for {:?}: {:?}", addr, e);
+    }
+    if let Err(e) = set_keepalive(socket) {
+        log::warn!("Cannot enable tcp keepalive for {:?}: {:?}", addr, e);
+    }
+}
+
+/// 为一个已经建立的连接注册客户端并开启读写任务，直到连接断开
+/// TCP和WebSocket连接共用这一套处理流程，它们只是消息的读写方式不同
+pub async fn serve_client<R, W, E>(mut frame_reader: R, mut frame_writer: W, addr: SocketAddr)
+    where R: Stream<Item = result::Result<Message, E>> + Unpin + Send + 'static,
+          E: Debug + Send,
+          W: Sink<Message> + Unpin + Send + 'static {
+    // 开启配对时，在注册和订阅广播之前先完成握手，没有配对的连接收不到任何消息
+    let first = if is_pairing_enabled() {
+        match pair_client(&mut frame_reader, &mut frame_writer, addr).await {
+            Some(handshake) => Some(handshake),
+            None => return
+        }
+    } else {
+        None
+    };
 
     let (msg_tx, msg_rx) = mpsc::channel::<Message>(4);
+    let broadcast_rx = CLIENT_BROADCAST.subscribe();
 
-    let ctx = VPadMessageContext { addr };
+    let (client_id, kick_rx) = GLOBAL_CLIENT_REGISTRY.lock().unwrap().register(addr, msg_tx.clone());
+    let ctx = VPadMessageContext { addr, client_id, daw: default_daw(), heartbeat: false };
 
     let mut read_task = tokio::spawn(async move {
-        read_from_client(frame_reader, msg_tx, ctx).await;
+        read_from_client(frame_reader, first, msg_tx, ctx).await;
     });
 
     let mut write_task = tokio::spawn(async move {
-        write_to_client(frame_writer, msg_rx).await;
+        write_to_client(frame_writer, msg_rx, broadcast_rx, client_id).await;
     });
 
-    if tokio::try_join!(&mut read_task, &mut write_task).is_err() {
-        log::info!("read/write task is terminated!");
-        read_task.abort();
-        write_task.abort();
+    // 注册表持有写通道的一个发送端，所以读任务结束后写任务不会自己结束，任何一方结束或被踢掉时都关闭连接
+    tokio::select! {
+        _ = &mut read_task => {},
+        _ = &mut write_task => {
+            log::info!("write task is terminated!");
+        },
+        Ok(()) = kick_rx => {
+            log::info!("{:?} is kicked", addr);
+        }
+    }
+    read_task.abort();
+    write_task.abort();
+    // abort只是请求取消，等任务真正结束后再注销，避免注销并松开音符之后读任务还在发出音符
+    // select中已经结束的任务不能再次await
+    if !read_task.is_finished() { let _ = read_task.await; }
+    if !write_task.is_finished() { let _ = write_task.await; }
+    let client = GLOBAL_CLIENT_REGISTRY.lock().unwrap().unregister(client_id);
+    if let Some(client) = client {
+        log::info!("{:?} disconnected, releasing its notes", addr);
+        release_client(&client);
+    }
+}
+
+/// 连接的第一条消息必须是出示了PIN或者密钥的握手，并且要在PAIRING_HANDSHAKE_TIMEOUT内到达
+/// 通过时返回这条握手，它随后像普通消息一样被处理；用PIN配对成功时先把密钥发给客户端
+/// 没有通过时等待PAIRING_FAILURE_DELAY再断开，拖慢猜测PIN的速度
+async fn pair_client<R, W, E>(frame_reader: &mut R, frame_writer: &mut W, addr: SocketAddr) -> Option<Message>
+    where R: Stream<Item = result::Result<Message, E>> + Unpin,
+          E: Debug,
+          W: Sink<Message> + Unpin {
+    let handshake = match tokio::time::timeout(PAIRING_HANDSHAKE_TIMEOUT, frame_reader.next()).await {
+        Ok(Some(Ok(msg))) => msg,
+        Ok(Some(Err(e))) => {
+            log::info!("Read handshake from {:?} error: {:?}", addr, e);
+            return None;
+        }
+        Ok(None) => return None,
+        Err(_) => {
+            log::info!("{:?} did not handshake in {:?}, disconnecting", addr, PAIRING_HANDSHAKE_TIMEOUT);
+            return None;
+        }
+    };
+    match authorize(&handshake, addr.ip()).await {
+        Ok(key) => {
+            if let Some(key) = key {
+                log::info!("{:?} is paired", addr);
+                if frame_writer.send(Message::PairMessage { key }).await.is_err() {
+                    return None;
+                }
+            }
+            Some(handshake)
+        }
+        Err(e) => {
+            log::warn!("{:?} is not paired, disconnecting: {}", addr, e);
+            tokio::time::sleep(PAIRING_FAILURE_DELAY).await;
+            None
+        }
     }
 }
 
-async fn read_from_client(mut reader: MessageFramedStream, msg_tx: mpsc::Sender<Message>, ctx: VPadMessageContext) {
+async fn read_from_client<R, E>(mut reader: R, first: Option<Message>, msg_tx: mpsc::Sender<Message>, mut ctx: VPadMessageContext)
+    where R: Stream<Item = result::Result<Message, E>> + Unpin, E: Debug {
+    if let Some(msg) = first {
+        dispatch(msg, &msg_tx, &mut ctx).await;
+    }
     loop {
-        match reader.next().await {
+        // 支持心跳的客户端每隔PING_INTERVAL就会回复一次Pong，长时间没有任何消息说明连接已经断了
+        let next = if ctx.heartbeat {
+            match tokio::time::timeout(IDLE_TIMEOUT, reader.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    log::info!("{:?} has been idle for {:?}, disconnecting", ctx.addr, IDLE_TIMEOUT);
+                    break;
+                }
+            }
+        } else {
+            reader.next().await
+        };
+        match next {
             None => {
                 log::info!("Client closed");
                 break;
@@ -104,36 +436,108 @@ async fn read_from_client(mut reader: MessageFramedStream, msg_tx: mpsc::Sender<
             Some(Err(e)) => {
                 log::info!("Read from client error: {:?}", e);
             }
-            Some(Ok(msg)) => {
-                log::debug!("Got an message => {:?}", msg);
-                if let Some(return_msg) = msg.handle_and_return(&ctx) {
-                    log::debug!("Return msg => {:?}", return_msg);
-                    if msg_tx.send(return_msg).await.is_err() {
-                        log::error!("Error to send return msg to sender channel");
-                    }
+            Some(Ok(msg)) => dispatch(msg, &msg_tx, &mut ctx).await
+        }
+    }
+}
+
+async fn dispatch(msg: Message, msg_tx: &mpsc::Sender<Message>, ctx: &mut VPadMessageContext) {
+    log::debug!("Got an message => {:?}", msg);
+    GLOBAL_CLIENT_REGISTRY.lock().unwrap().touch(ctx.client_id);
+    if let Some(return_msg) = msg.handle_and_return(ctx) {
+        log::debug!("Return msg => {:?}", return_msg);
+        if msg_tx.send(return_msg).await.is_err() {
+            log::error!("Error to send return msg to sender channel");
+        }
+    }
+}
+
+async fn write_to_client<W>(mut writer: W, mut msg_rx: mpsc::Receiver<Message>, mut broadcast_rx: broadcast::Receiver<Message>, client_id: u64)
+    where W: Sink<Message> + Unpin {
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    let mut shutdown_rx = SHUTDOWN_SIGNAL.subscribe();
+    loop {
+        if *shutdown_rx.borrow() {
+            // 只有认识心跳的客户端才会认识Shutdown Message
+            let announce = GLOBAL_CLIENT_REGISTRY.lock().unwrap().supports_heartbeat(client_id);
+            drain_and_close(writer, msg_rx, announce).await;
+            break;
+        }
+        let msg = tokio::select! {
+            _ = shutdown_rx.changed() => continue,
+            _ = ping_interval.tick() => match GLOBAL_CLIENT_REGISTRY.lock().unwrap().start_ping(client_id) {
+                Some(seq) => Message::PingMessage { seq },
+                None => continue
+            },
+            msg = msg_rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break
+            },
+            msg = broadcast_rx.recv() => match msg {
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Client is too slow, {} broadcast msgs are skipped", n);
+                    continue;
                 }
+                Err(broadcast::error::RecvError::Closed) => break
             }
+        };
+        if writer.send(msg).await.is_err() {
+            log::error!("Error to sink msg to client");
         }
     }
 }
 
-async fn write_to_client(mut writer: MessageFramedSink, mut msg_rx: mpsc::Receiver<Message>) {
-    while let Some(msg) = msg_rx.recv().await {
+/// 服务端关闭时，发完队列中已有的消息，announce为true时告诉客户端服务端正在关闭，然后关闭连接
+async fn drain_and_close<W>(mut writer: W, mut msg_rx: mpsc::Receiver<Message>, announce: bool)
+    where W: Sink<Message> + Unpin {
+    while let Ok(msg) = msg_rx.try_recv() {
         if writer.send(msg).await.is_err() {
-            log::error!("Error to sink msg to client");
+            return;
         }
     }
+    if announce {
+        let _ = writer.send(Message::ShutdownMessage).await;
+    }
+    let _ = writer.close().await;
+}
+
+/// 开启TCP keepalive，客户端不支持心跳时，由操作系统探测已经消失的客户端
+fn set_keepalive(socket: &TcpStream) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE_TIME)
+        .with_interval(KEEPALIVE_INTERVAL);
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
 }
 
+pub const DEFAULT_PORT: u16 = 1236;
+const DEFAULT_BACKLOG: u32 = 1024;
+const MAX_PORT_FALLBACK: u16 = 10;
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+const SHUTDOWN_KICK_TIMEOUT: Duration = Duration::from_secs(1);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const PAIRING_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const PAIRING_FAILURE_DELAY: Duration = Duration::from_secs(1);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(3);
+
 // ------ 错误封装 ------ //
 #[derive(Debug)]
 pub enum VPadServerError {
     IOError(Error),
-    CloseError(SendError<()>)
+    WebSocketError(tokio_tungstenite::tungstenite::Error),
+    CodecError(MessageCodecError)
+}
+impl From<tokio_tungstenite::tungstenite::Error> for VPadServerError {
+    fn from(value: tokio_tungstenite::tungstenite::Error) -> Self {
+        VPadServerError::WebSocketError(value)
+    }
 }
-impl From<SendError<()>> for VPadServerError {
-    fn from(value: SendError<()>) -> Self {
-        VPadServerError::CloseError(value)
+impl From<MessageCodecError> for VPadServerError {
+    fn from(value: MessageCodecError) -> Self {
+        VPadServerError::CodecError(value)
     }
 }
 impl From<Error> for VPadServerError {
@@ -142,3 +546,73 @@ impl From<Error> for VPadServerError {
     }
 }
 // ------ 错误封装 ------ //
+
+#[cfg(test)]
+mod server_test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use tokio::sync::mpsc;
+    use crate::message::Message;
+    use crate::server::{drain_and_close, VPadServer};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_fallback_to_next_free_port() {
+        let mut first = VPadServer::bind(LOCALHOST, 0);
+        let taken = first.listen().unwrap();
+        let mut second = VPadServer::bind(LOCALHOST, taken);
+        let port = second.listen().unwrap();
+        assert!(port > taken && port <= taken + 10);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_when_udp_port_is_taken() {
+        let udp = std::net::UdpSocket::bind((LOCALHOST, 0)).unwrap();
+        let taken = udp.local_addr().unwrap().port();
+        let mut server = VPadServer::bind(LOCALHOST, taken);
+        let port = server.listen().unwrap();
+        assert!(port > taken && port <= taken + 10);
+        assert_eq!(server.udp_sockets[0].local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_listen_on_multiple_addresses() {
+        let mut server = VPadServer::bind(LOCALHOST, 0).with_address(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        // 没有IPv6的环境下跳过
+        let port = match server.listen() {
+            Ok(port) => port,
+            Err(_) => return
+        };
+        assert_eq!(server.listeners.len(), 2);
+        assert_eq!(server.udp_sockets.len(), 2);
+        for listener in &server.listeners {
+            assert_eq!(listener.local_addr().unwrap().port(), port);
+        }
+        for socket in &server.udp_sockets {
+            assert_eq!(socket.local_addr().unwrap().port(), port);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_queued_messages_before_shutdown() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(Message::PingMessage { seq: 1 }).await.unwrap();
+        tx.send(Message::PongMessage { seq: 2 }).await.unwrap();
+        let mut sent: Vec<Message> = Vec::new();
+        drain_and_close(&mut sent, rx, true).await;
+        assert_eq!(sent.len(), 3);
+        assert!(matches!(sent[0], Message::PingMessage { seq: 1 }));
+        assert!(matches!(sent[1], Message::PongMessage { seq: 2 }));
+        assert!(matches!(sent[2], Message::ShutdownMessage));
+    }
+
+    #[tokio::test]
+    async fn test_old_clients_are_not_told_about_shutdown() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(Message::PongMessage { seq: 1 }).await.unwrap();
+        let mut sent: Vec<Message> = Vec::new();
+        drain_and_close(&mut sent, rx, false).await;
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent[0], Message::PongMessage { seq: 1 }));
+    }
+}
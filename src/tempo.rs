@@ -0,0 +1,133 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+
+/// Arp与Chord Message中速度（bpm）的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempoSource {
+    // 使用客户端在消息中给出的bpm
+    Client,
+    // 使用DAW通过反馈端口发送的MIDI时钟，DAW没有在发送时钟时退回到客户端的bpm
+    Daw,
+}
+
+impl FromStr for TempoSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(TempoSource::Client),
+            "daw" => Ok(TempoSource::Daw),
+            _ => Err(format!("unknown tempo source '{}', expect client or daw", s))
+        }
+    }
+}
+
+impl Display for TempoSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TempoSource::Client => write!(f, "client"),
+            TempoSource::Daw => write!(f, "daw"),
+        }
+    }
+}
+
+/// 根据MIDI时钟估算DAW的速度，每拍24个时钟
+/// 单个时钟的间隔受MIDI驱动的抖动影响很大，所以对间隔做指数平滑
+pub struct MidiClock {
+    last_tick: Option<Instant>,
+    interval: Option<Duration>,
+}
+
+impl MidiClock {
+    pub fn new() -> MidiClock {
+        MidiClock { last_tick: None, interval: None }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        if let Some(last_tick) = self.last_tick {
+            let elapsed = now.saturating_duration_since(last_tick);
+            // 停顿之后重新开始时不沿用停顿前的间隔
+            self.interval = if elapsed > CLOCK_TIMEOUT {
+                None
+            } else {
+                Some(match self.interval {
+                    Some(interval) => interval.mul_f64(1.0 - CLOCK_SMOOTHING) + elapsed.mul_f64(CLOCK_SMOOTHING),
+                    None => elapsed
+                })
+            };
+        }
+        self.last_tick = Some(now);
+    }
+
+    /// DAW超过CLOCK_TIMEOUT没有发送时钟时返回None
+    pub fn bpm(&self, now: Instant) -> Option<i16> {
+        let last_tick = self.last_tick?;
+        if now.saturating_duration_since(last_tick) > CLOCK_TIMEOUT {
+            return None;
+        }
+        let interval = self.interval.filter(|interval| !interval.is_zero())?;
+        Some((60.0 / (interval.as_secs_f64() * CLOCKS_PER_BEAT)).round().min(i16::MAX as f64) as i16)
+    }
+}
+
+lazy_static! {
+    // 默认使用客户端的bpm，与没有速度来源之前的行为一致
+    static ref TEMPO_SOURCE: Mutex<TempoSource> = Mutex::new(TempoSource::Client);
+    static ref MIDI_CLOCK: Mutex<MidiClock> = Mutex::new(MidiClock::new());
+}
+
+pub fn set_tempo_source(source: TempoSource) {
+    *TEMPO_SOURCE.lock().unwrap() = source;
+}
+
+/// 反馈端口收到了一个MIDI时钟
+pub fn on_midi_clock() {
+    MIDI_CLOCK.lock().unwrap().tick(Instant::now());
+}
+
+/// 按速度来源决定Arp与Chord实际使用的bpm
+pub fn effective_bpm(client_bpm: i16) -> i16 {
+    match *TEMPO_SOURCE.lock().unwrap() {
+        TempoSource::Client => client_bpm,
+        TempoSource::Daw => MIDI_CLOCK.lock().unwrap().bpm(Instant::now()).unwrap_or(client_bpm)
+    }
+}
+
+pub const MIDI_CLOCK_STATUS: u8 = 0xF8;
+
+const CLOCKS_PER_BEAT: f64 = 24.0;
+const CLOCK_SMOOTHING: f64 = 0.1;
+// 20bpm时两个时钟的间隔是125ms
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[cfg(test)]
+mod tempo_test {
+    use std::time::{Duration, Instant};
+    use crate::tempo::{MidiClock, TempoSource};
+
+    #[test]
+    fn test_bpm_from_clock() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        assert_eq!(clock.bpm(start), None);
+        // 120bpm时每个时钟间隔60 / 120 / 24秒
+        let interval = Duration::from_secs_f64(60.0 / 120.0 / 24.0);
+        let mut now = start;
+        for _ in 0..48 {
+            clock.tick(now);
+            now += interval;
+        }
+        assert_eq!(clock.bpm(now), Some(120));
+        assert_eq!(clock.bpm(now + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn test_parse_tempo_source() {
+        assert_eq!("daw".parse::<TempoSource>(), Ok(TempoSource::Daw));
+        assert_eq!(TempoSource::Client.to_string().parse::<TempoSource>(), Ok(TempoSource::Client));
+        assert!("midi".parse::<TempoSource>().is_err());
+    }
+}
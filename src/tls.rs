@@ -0,0 +1,153 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use crate::public::write_private_file;
+
+/// TCP监听端口上的TLS，证书是第一次开启时生成的自签名证书
+/// 客户端无法通过CA验证自签名证书，应当固定（pin）二维码中给出的证书指纹
+/// TLS之上的消息格式与明文连接完全相同
+struct TlsIdentity {
+    acceptor: TlsAcceptor,
+    // 证书DER的SHA-256，小写十六进制
+    fingerprint: String,
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    IOError(PathBuf, std::io::Error),
+    CertError(String),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::IOError(path, e) => write!(f, "cannot access tls certificate {:?}: {}", path, e),
+            TlsError::CertError(e) => write!(f, "invalid tls certificate: {}", e),
+        }
+    }
+}
+
+lazy_static! {
+    // 为None时没有开启TLS，TCP连接是明文的
+    static ref GLOBAL_TLS: Mutex<Option<TlsIdentity>> = Mutex::new(None);
+}
+
+/// 开启TLS，证书不存在时在dir中生成，返回证书指纹
+pub fn enable_tls(dir: &Path) -> Result<String, TlsError> {
+    let identity = load_or_generate_identity(dir)?;
+    let fingerprint = identity.fingerprint.clone();
+    *GLOBAL_TLS.lock().unwrap() = Some(identity);
+    Ok(fingerprint)
+}
+
+pub fn tls_acceptor() -> Option<TlsAcceptor> {
+    GLOBAL_TLS.lock().unwrap().as_ref().map(|identity| identity.acceptor.clone())
+}
+
+/// 当前证书的指纹，没有开启TLS时为None
+pub fn tls_fingerprint() -> Option<String> {
+    GLOBAL_TLS.lock().unwrap().as_ref().map(|identity| identity.fingerprint.clone())
+}
+
+pub fn default_tls_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("vpad").join("tls"))
+}
+
+fn load_or_generate_identity(dir: &Path) -> Result<TlsIdentity, TlsError> {
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        generate_self_signed(&cert_path, &key_path)?;
+        log::info!("Generated a self-signed tls certificate in {:?}", dir);
+    }
+    let cert = read_pem(&cert_path, rustls_pemfile::certs)?;
+    let key = read_pem(&key_path, rustls_pemfile::pkcs8_private_keys)?;
+    let fingerprint = fingerprint_of(&cert);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(cert)], PrivateKey(key))
+        .map_err(|e| TlsError::CertError(e.to_string()))?;
+    Ok(TlsIdentity { acceptor: TlsAcceptor::from(Arc::new(config)), fingerprint })
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> Result<(), TlsError> {
+    let cert = rcgen::generate_simple_self_signed(vec![CERT_SUBJECT_NAME.to_string()])
+        .map_err(|e| TlsError::CertError(e.to_string()))?;
+    let cert_pem = cert.serialize_pem().map_err(|e| TlsError::CertError(e.to_string()))?;
+    if let Some(dir) = cert_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| TlsError::IOError(dir.to_path_buf(), e))?;
+    }
+    fs::write(cert_path, cert_pem).map_err(|e| TlsError::IOError(cert_path.to_path_buf(), e))?;
+    // 私钥只有当前用户可以读取
+    write_private_file(key_path, cert.serialize_private_key_pem().as_bytes()).map_err(|e| TlsError::IOError(key_path.to_path_buf(), e))
+}
+
+/// 读取PEM文件中的第一个条目
+fn read_pem<F>(path: &Path, parse: F) -> Result<Vec<u8>, TlsError>
+    where F: FnOnce(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>> {
+    let file = fs::File::open(path).map_err(|e| TlsError::IOError(path.to_path_buf(), e))?;
+    let items = parse(&mut BufReader::new(file)).map_err(|e| TlsError::IOError(path.to_path_buf(), e))?;
+    items.into_iter().next().ok_or_else(|| TlsError::CertError(format!("{:?} is empty", path)))
+}
+
+fn fingerprint_of(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const CERT_SUBJECT_NAME: &str = "localhost";
+
+#[cfg(test)]
+mod tls_test {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+    use tokio_util::codec::Framed;
+    use crate::message::Message;
+    use crate::message_codec::MessageCodec;
+    use crate::tls::{CERT_FILE, CERT_SUBJECT_NAME, load_or_generate_identity, read_pem};
+
+    #[test]
+    fn test_certificate_is_generated_once() {
+        let dir = env::temp_dir().join(format!("vpad_tls_once_{}", std::process::id()));
+        let first = load_or_generate_identity(&dir).unwrap();
+        let second = load_or_generate_identity(&dir).unwrap();
+        assert_eq!(first.fingerprint.len(), 64);
+        assert_eq!(first.fingerprint, second.fingerprint);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// 信任自签名证书的客户端可以完成握手，TLS之上仍然是相同的消息帧
+    #[tokio::test]
+    async fn test_framed_messages_over_tls() {
+        let dir = env::temp_dir().join(format!("vpad_tls_frame_{}", std::process::id()));
+        let identity = load_or_generate_identity(&dir).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(read_pem(&dir.join(CERT_FILE), rustls_pemfile::certs).unwrap())).unwrap();
+        let client_config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let stream = identity.acceptor.accept(server).await.unwrap();
+            Framed::new(stream, MessageCodec{}).next().await.unwrap().unwrap()
+        });
+        let name = ServerName::try_from(CERT_SUBJECT_NAME).unwrap();
+        let stream = connector.connect(name, client).await.unwrap();
+        let mut framed = Framed::new(stream, MessageCodec{});
+        framed.send(Message::PingMessage { seq: 7 }).await.unwrap();
+        assert!(matches!(server.await.unwrap(), Message::PingMessage { seq: 7 }));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,12 @@
 use midi_control::Channel;
+use crate::daw_state::on_fader_touched;
 use crate::message::Message;
 use crate::midi_connect::GLOBAL_CTL_CONNECTOR;
+use crate::notice::Notice;
 
 
-fn send_on_and_off(note: i8, velocity: i8) {
+/// 在控制端口上按下并松开一个按钮音符
+pub(crate) fn send_on_and_off(note: i8, velocity: i8) {
     GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, velocity, 1);
     GLOBAL_CTL_CONNECTOR.lock().unwrap().midi_note_message(note, velocity, 0);
 }
@@ -30,27 +33,41 @@ fn map_num_to_channel(num: i8) -> Channel {
     }
 }
 
-pub fn handle_track_message(msg: Message) {
-    // 第nth个轨道，设置状态为state，如果状态时FADER_VALUE_CHANEGD，设置value
-    if let Message::TrackMessage { nth, state, value } = msg {
+pub fn handle_track_message(msg: Message) -> Result<(), Notice> {
+    // 第nth个轨道，设置状态为state，如果状态时FADER_VALUE_CHANEGD，设置value（或14位的fine_value）
+    if let Message::TrackMessage { nth, state, value, fine_value } = msg {
+        // 推子包括Master推子（第9个），其它按钮只有8个轨道
+        let max_nth = if state <= STATE_FADER_VALUE_CHANGED { 9 } else { 8 };
+        if !(1..=max_nth).contains(&nth) {
+            return Err(Notice::InvalidTrack(nth));
+        }
         match state {
-            STATE_FADER_UP => send_on_and_off(TRACK_FADER_TOUCH_NOTE_OFFSET + nth - 1, 0),
-            STATE_FADER_DOWN => send_on_and_off(TRACK_FADER_TOUCH_NOTE_OFFSET + nth - 1, 127),
-            STATE_FADER_VALUE_CHANGED => GLOBAL_CTL_CONNECTOR.lock().unwrap().pitch_wheel_message_with_channel(value, map_num_to_channel(nth)),
+            STATE_FADER_UP => {
+                send_on_and_off(TRACK_FADER_TOUCH_NOTE_OFFSET + nth - 1, 0);
+                on_fader_touched((nth - 1) as usize, false);
+            },
+            STATE_FADER_DOWN => {
+                send_on_and_off(TRACK_FADER_TOUCH_NOTE_OFFSET + nth - 1, 127);
+                on_fader_touched((nth - 1) as usize, true);
+            },
+            STATE_FADER_VALUE_CHANGED => match fine_value {
+                // 新版本客户端携带14位的值，旧版本客户端只有7位
+                Some(fine_value) => GLOBAL_CTL_CONNECTOR.lock().unwrap().pitch_wheel_14bit_message_with_channel(fine_value, map_num_to_channel(nth)),
+                None => GLOBAL_CTL_CONNECTOR.lock().unwrap().pitch_wheel_message_with_channel(value, map_num_to_channel(nth))
+            },
             STATE_SOLO_ON | STATE_SOLO_OFF => send_on_and_off(TRACK_SOLO_NOTE_OFFSET + nth - 1, 127),
             STATE_MUTE_ON | STATE_MUTE_OFF => send_on_and_off(TRACK_MUTE_NOTE_OFFSET + nth - 1, 127),
             STATE_REC_ON | STATE_REC_OFF => send_on_and_off(TRACK_REC_NOTE_OFFSET + nth - 1, 127),
-            _ => {
-                log::error!("cannot handle track message since state is invaild {}", state);
-            }
+            _ => return Err(Notice::InvalidTrackState(state))
         }
     }
+    Ok(())
 }
 
 
-const STATE_FADER_UP: i8 = 0;
-const STATE_FADER_DOWN: i8 = 1;
-const STATE_FADER_VALUE_CHANGED: i8 = 2;
+pub const STATE_FADER_UP: i8 = 0;
+pub const STATE_FADER_DOWN: i8 = 1;
+pub const STATE_FADER_VALUE_CHANGED: i8 = 2;
 const STATE_SOLO_ON: i8 = 3;
 const STATE_SOLO_OFF: i8 = 4;
 const STATE_MUTE_ON: i8 = 5;
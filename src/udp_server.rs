@@ -0,0 +1,153 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use bytes::{Buf, BytesMut};
+use tokio::net::UdpSocket;
+use tokio_util::codec::Decoder;
+use crate::client_registry::GLOBAL_CLIENT_REGISTRY;
+use crate::control_handler::default_daw;
+use crate::message::Message;
+use crate::message_codec::MessageCodec;
+use crate::server::VPadMessageContext;
+use crate::tls::tls_fingerprint;
+
+/// UDP通道，用于在拥塞的Wi-Fi上低延迟地传输Midi、PitchWheel和CC消息
+/// 客户端通过TCP上的UdpSession Message拿到session后，把消息发到与TCP相同的端口
+/// 每个数据报是 session: int4, seq: int4, 以及一个与TCP上格式相同的消息帧
+/// 客户端可以把同一个数据报发送多次来对抗丢包，服务端按seq去重
+/// socket由VPadServer::listen与TCP端口一起绑定，保证端口回退时UDP也使用同一个端口
+pub async fn serve_udp(socket: std::net::UdpSocket) {
+    let socket = match UdpSocket::from_std(socket) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Cannot use udp socket, udp channel is disabled: {:?}", e);
+            return;
+        }
+    };
+    let port = socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    log::info!("Udp channel is listening on {:?}", socket.local_addr());
+    UDP_PORT.store(port, Ordering::SeqCst);
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, src)) => handle_datagram(&buf[..len], src),
+            Err(e) => log::info!("Read from udp error: {:?}", e)
+        }
+    }
+}
+
+/// UDP通道的端口，没有开启时为0
+pub fn udp_port() -> u16 {
+    UDP_PORT.load(Ordering::SeqCst)
+}
+
+/// 分配UDP session时告诉客户端的端口，为0时不分配session
+/// UDP数据报是明文的，开启TLS时不提供UDP通道，否则音符和session会绕过加密
+pub fn udp_session_port() -> u16 {
+    if tls_fingerprint().is_some() { 0 } else { udp_port() }
+}
+
+/// 先校验session、来源IP和seq，只有属于已连接客户端的数据报才会被解码
+/// 任何人都可以向这个端口发送数据报，所以这里的日志都是debug级别
+fn handle_datagram(bytes: &[u8], src: SocketAddr) {
+    if bytes.len() < DATAGRAM_HEADER_SIZE { return; }
+    let mut bytes = BytesMut::from(bytes);
+    let session = bytes.get_u32();
+    let seq = bytes.get_u32();
+    let (client_id, addr) = match GLOBAL_CLIENT_REGISTRY.lock().unwrap().accept_udp(session, src.ip(), seq) {
+        Some(client) => client,
+        None => return
+    };
+    let decoded = MessageCodec{}.decode(&mut bytes);
+    let msg = match decoded {
+        Ok(Some(msg)) => msg,
+        _ => {
+            log::debug!("Got an invaild udp datagram from {:?}", src);
+            return;
+        }
+    };
+    if !matches!(msg, Message::Midi { .. } | Message::PitchWheel { .. } | Message::CC { .. }) {
+        log::debug!("{:?} is not allowed on udp channel", msg);
+        return;
+    }
+    log::debug!("Got an udp message => {:?}", msg);
+    let mut ctx = VPadMessageContext { addr, client_id, daw: default_daw(), heartbeat: false };
+    msg.handle_and_return(&mut ctx);
+}
+
+/// 序号滑动窗口，记录最大的seq以及它之前SEQ_WINDOW_SIZE个seq是否已经收到过
+/// 重复的以及比窗口更旧的数据报会被丢弃，窗口内乱序到达的数据报仍然被接受
+pub struct SeqWindow {
+    highest: Option<u32>,
+    // 第n位代表highest - n是否已经收到
+    received: u64,
+}
+
+impl SeqWindow {
+    pub fn new() -> SeqWindow {
+        SeqWindow { highest: None, received: 0 }
+    }
+
+    /// seq是第一次收到时返回true
+    pub fn accept(&mut self, seq: u32) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.received = 1;
+                return true;
+            }
+        };
+        if seq > highest {
+            let shift = seq - highest;
+            self.received = if shift >= SEQ_WINDOW_SIZE { 0 } else { self.received << shift };
+            self.received |= 1;
+            self.highest = Some(seq);
+            return true;
+        }
+        let offset = highest - seq;
+        if offset >= SEQ_WINDOW_SIZE || self.received & (1 << offset) != 0 {
+            return false;
+        }
+        self.received |= 1 << offset;
+        true
+    }
+}
+
+static UDP_PORT: AtomicU16 = AtomicU16::new(0);
+
+const MAX_DATAGRAM_SIZE: usize = 1500;
+const DATAGRAM_HEADER_SIZE: usize = 8;
+const SEQ_WINDOW_SIZE: u32 = 64;
+
+#[cfg(test)]
+mod udp_server_test {
+    use crate::udp_server::SeqWindow;
+
+    #[test]
+    fn test_duplicated_seq_is_dropped() {
+        let mut window = SeqWindow::new();
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+        assert!(!window.accept(11));
+    }
+
+    #[test]
+    fn test_out_of_order_seq_in_window() {
+        let mut window = SeqWindow::new();
+        assert!(window.accept(10));
+        assert!(window.accept(12));
+        assert!(window.accept(11));
+        assert!(!window.accept(11));
+        assert!(!window.accept(10));
+    }
+
+    #[test]
+    fn test_too_old_seq_is_dropped() {
+        let mut window = SeqWindow::new();
+        assert!(window.accept(100));
+        assert!(window.accept(200));
+        assert!(!window.accept(100));
+        assert!(window.accept(150));
+    }
+}
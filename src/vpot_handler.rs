@@ -0,0 +1,89 @@
+use crate::message::Message;
+use crate::midi_connect::GLOBAL_CTL_CONNECTOR;
+use crate::notice::Notice;
+use crate::track_handler::send_on_and_off;
+
+/// V-Pot是MCU上每个轨道的旋钮，它使用相对值编码
+/// 在DAW中，V-Pot控制的是声像、发送量还是插件参数，取决于DAW当前的assignment模式
+pub fn handle_vpot_message(msg: Message) -> Result<(), Notice> {
+    if let Message::VPotMessage { nth, state, ticks, acceleration } = msg {
+        if !(1..=8).contains(&nth) {
+            return Err(Notice::InvalidVPot(nth));
+        }
+        match state {
+            VPOT_STATE_ROTATE => {
+                let ticks = accelerate(ticks, acceleration);
+                send_relative_ticks(VPOT_CC_OFFSET + nth - 1, ticks);
+            }
+            VPOT_STATE_PUSH_DOWN => send_on_and_off(VPOT_PUSH_NOTE_OFFSET + nth - 1, 127),
+            VPOT_STATE_PUSH_UP => send_on_and_off(VPOT_PUSH_NOTE_OFFSET + nth - 1, 0),
+            _ => return Err(Notice::InvalidVPotState(state))
+        }
+    }
+    Ok(())
+}
+
+/// 发送相对值
+pub fn send_relative_ticks(cc: i8, ticks: i32) {
+    for value in relative_cc_values(ticks) {
+        GLOBAL_CTL_CONNECTOR.lock().unwrap().cc_message(cc, value);
+    }
+}
+
+/// 一条CC消息最多携带63个tick，超出的部分拆成多条
+pub fn relative_cc_values(ticks: i32) -> Vec<i8> {
+    let mut values = Vec::new();
+    let mut remaind = ticks;
+    while remaind != 0 {
+        let once = remaind.clamp(-MAX_TICKS_PER_MESSAGE, MAX_TICKS_PER_MESSAGE);
+        values.push(relative_cc_value(once));
+        remaind -= once;
+    }
+    values
+}
+
+/// 加速曲线，acceleration是0~100的百分数，为0时不加速
+/// 一次转动的tick越多，说明用户转得越快，额外增加的tick与tick数的平方成正比
+pub fn accelerate(ticks: i8, acceleration: i8) -> i32 {
+    let ticks = ticks as i32;
+    let acceleration = acceleration.clamp(0, 100) as i32;
+    let extra = ticks * ticks.abs() * acceleration / 100;
+    ticks + extra
+}
+
+/// MCU的相对值编码，低6位是tick数，第7位为1时代表逆时针
+pub fn relative_cc_value(ticks: i32) -> i8 {
+    let count = ticks.abs().min(MAX_TICKS_PER_MESSAGE) as i8;
+    if ticks < 0 { 0x40 | count } else { count }
+}
+
+const VPOT_STATE_ROTATE: i8 = 0;
+const VPOT_STATE_PUSH_DOWN: i8 = 1;
+const VPOT_STATE_PUSH_UP: i8 = 2;
+
+const VPOT_CC_OFFSET: i8 = 16;
+const VPOT_PUSH_NOTE_OFFSET: i8 = 32;
+const MAX_TICKS_PER_MESSAGE: i32 = 0x3F;
+
+#[cfg(test)]
+mod vpot_handler_test {
+    use crate::vpot_handler::{accelerate, relative_cc_value};
+
+    #[test]
+    fn test_relative_cc_value() {
+        assert_eq!(relative_cc_value(1), 0x01);
+        assert_eq!(relative_cc_value(-1), 0x41);
+        assert_eq!(relative_cc_value(63), 0x3F);
+        assert_eq!(relative_cc_value(-100), 0x7F);
+    }
+
+    #[test]
+    fn test_accelerate() {
+        assert_eq!(accelerate(3, 0), 3);
+        assert_eq!(accelerate(-3, 0), -3);
+        assert_eq!(accelerate(1, 100), 2);
+        assert_eq!(accelerate(4, 50), 12);
+        assert_eq!(accelerate(-4, 50), -12);
+        assert_eq!(accelerate(4, 127), accelerate(4, 100));
+    }
+}
@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// 网页控制器，静态资源编译进二进制文件，与WebSocket共用同一个端口
+/// 浏览器打开 http://<ip>:<ws_port>/ 即可得到一个通过WebSocket连接回来的控制器
+const INDEX_HTML: &str = include_str!("../web/index.html");
+const PAD_JS: &str = include_str!("../web/pad.js");
+
+/// 在不消费数据的情况下读取HTTP请求头，判断它是不是WebSocket升级请求
+/// 请求头不完整时稍等再读，超过MAX_REQUEST_HEAD_SIZE或者等待超时都按普通HTTP请求处理
+pub async fn is_websocket_upgrade(socket: &TcpStream) -> bool {
+    let mut buf = [0u8; MAX_REQUEST_HEAD_SIZE];
+    for _ in 0..PEEK_RETRIES {
+        let n = match socket.peek(&mut buf).await {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => n
+        };
+        let head = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+        if head.contains("\r\n\r\n") || n == buf.len() {
+            return head.contains("upgrade: websocket");
+        }
+        tokio::time::sleep(PEEK_INTERVAL).await;
+    }
+    false
+}
+
+/// 处理一个普通的HTTP请求，返回网页控制器的静态资源，然后关闭连接
+pub async fn serve_web_pad(mut socket: TcpStream, addr: SocketAddr) {
+    let mut buf = [0u8; MAX_REQUEST_HEAD_SIZE];
+    let n = match socket.read(&mut buf).await {
+        Ok(n) => n,
+        Err(_) => return
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request_path(&request);
+    log::info!("{:?} requested web pad {}", addr, path);
+    let response = match asset(path) {
+        Some((content_type, body)) => response("200 OK", content_type, body),
+        None => response("404 Not Found", "text/plain", "Not Found")
+    };
+    let _ = socket.write_all(response.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
+
+/// 请求行形如 `GET /pad.js HTTP/1.1`，查询参数被忽略
+fn request_path(request: &str) -> &str {
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    path.split('?').next().unwrap_or("/")
+}
+
+fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    match path {
+        "/" | "/index.html" => Some(("text/html; charset=utf-8", INDEX_HTML)),
+        "/pad.js" => Some(("application/javascript; charset=utf-8", PAD_JS)),
+        _ => None
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body)
+}
+
+const MAX_REQUEST_HEAD_SIZE: usize = 4096;
+const PEEK_RETRIES: usize = 100;
+const PEEK_INTERVAL: Duration = Duration::from_millis(20);
+
+#[cfg(test)]
+mod web_pad_test {
+    use crate::web_pad::{asset, request_path};
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET / HTTP/1.1\r\nHost: a\r\n\r\n"), "/");
+        assert_eq!(request_path("GET /pad.js?v=2 HTTP/1.1\r\n\r\n"), "/pad.js");
+        assert_eq!(request_path(""), "/");
+    }
+
+    #[test]
+    fn test_asset() {
+        assert!(asset("/").unwrap().1.contains("pad.js"));
+        assert!(asset("/pad.js").is_some());
+        assert!(asset("/../Cargo.toml").is_none());
+    }
+}
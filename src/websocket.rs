@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use bytes::BytesMut;
+use futures_util::{future, stream, Sink, SinkExt, Stream, StreamExt};
+use futures_util::stream::{SplitSink, SplitStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::message::Message;
+use crate::message_codec::MessageCodec;
+use crate::server::{prepare_socket, serve_client, VPadServerError};
+use crate::web_pad::{is_websocket_upgrade, serve_web_pad};
+
+/// WebSocket监听，供无法使用原始TCP的浏览器客户端连接，同时提供网页控制器
+/// 每个二进制WebSocket帧中携带的是与TCP上格式相同的消息帧，一个WebSocket帧中可以有多条消息
+pub async fn serve_websocket(listener: TcpListener) {
+    log::info!("Websocket is listening on {:?}", listener.local_addr());
+    loop {
+        if let Ok((socket, addr)) = listener.accept().await {
+            tokio::spawn(process_websocket(socket, addr));
+        }
+    }
+}
+
+async fn process_websocket(socket: TcpStream, addr: SocketAddr) {
+    // 同一个端口上还提供网页控制器，不是WebSocket升级请求的都当作网页请求
+    if !is_websocket_upgrade(&socket).await {
+        serve_web_pad(socket, addr).await;
+        return;
+    }
+    log::info!("Got a new websocket connection from: {:?}", addr);
+    prepare_socket(&socket, addr);
+    let websocket = match tokio_tungstenite::accept_async(socket).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            log::info!("Websocket handshake with {:?} failed: {:?}", addr, e);
+            return;
+        }
+    };
+    let (ws_writer, ws_reader) = websocket.split();
+    serve_client(message_reader(ws_reader), message_writer(ws_writer), addr).await;
+}
+
+/// 把WebSocket帧流转换为消息流
+fn message_reader(reader: SplitStream<WebSocketStream<TcpStream>>)
+    -> impl Stream<Item = Result<Message, VPadServerError>> + Unpin + Send {
+    let mut codec = MessageCodec{};
+    let mut buffer = BytesMut::new();
+    reader.flat_map(move |frame| {
+        let messages = match frame {
+            Ok(WsMessage::Binary(bytes)) => {
+                buffer.extend_from_slice(&bytes);
+                decode_all(&mut codec, &mut buffer)
+            }
+            // Ping/Pong由tungstenite自动处理，文本帧不是VPad协议的一部分
+            Ok(_) => vec![],
+            Err(e) => vec![Err(VPadServerError::from(e))]
+        };
+        stream::iter(messages)
+    })
+}
+
+fn decode_all(codec: &mut MessageCodec, buffer: &mut BytesMut) -> Vec<Result<Message, VPadServerError>> {
+    let mut messages = vec![];
+    loop {
+        match codec.decode(buffer) {
+            Ok(Some(message)) => messages.push(Ok(message)),
+            Ok(None) => break,
+            Err(e) => {
+                buffer.clear();
+                messages.push(Err(VPadServerError::from(e)));
+                break;
+            }
+        }
+    }
+    messages
+}
+
+/// 把消息编码后作为二进制WebSocket帧发送
+fn message_writer(writer: SplitSink<WebSocketStream<TcpStream>, WsMessage>)
+    -> impl Sink<Message, Error = VPadServerError> + Unpin + Send {
+    writer.with(|message: Message| {
+        let mut buffer = BytesMut::new();
+        let frame = MessageCodec{}.encode(message, &mut buffer)
+            .map(|_| WsMessage::Binary(buffer.to_vec()))
+            .map_err(VPadServerError::from);
+        future::ready(frame)
+    })
+}
+
+#[cfg(test)]
+mod websocket_test {
+    use bytes::BytesMut;
+    use crate::message::Message::Midi;
+    use crate::message_codec::MessageCodec;
+    use crate::websocket::decode_all;
+
+    #[test]
+    fn test_decode_all_keeps_partial_message() {
+        let mut codec = MessageCodec{};
+        // 两条完整的Midi Message，以及第三条的前半部分
+        let mut buffer = BytesMut::from(&[0, 5, 2, 60, 100, 1, 1, 0, 5, 2, 62, 100, 1, 1, 0, 5, 2][..]);
+        let messages = decode_all(&mut codec, &mut buffer);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[1], Ok(Midi { note: 62, .. })));
+        assert_eq!(buffer.len(), 3);
+
+        buffer.extend_from_slice(&[64, 100, 1, 1]);
+        let messages = decode_all(&mut codec, &mut buffer);
+        assert!(matches!(messages[0], Ok(Midi { note: 64, .. })));
+        assert!(buffer.is_empty());
+    }
+}
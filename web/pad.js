@@ -0,0 +1,257 @@
+// VPad网页控制器，通过WebSocket使用VPad协议（见PROTOCAL.md）与服务端通信
+const OP = {
+  HANDSHAKE: 1, MIDI: 2, ARP: 3, CHORD: 4, PITCHWHEEL: 5, CONTROL: 8,
+  NOTICE: 15, PANIC: 16, PING: 17, PONG: 18, PAIR: 20, SHUTDOWN: 21,
+};
+const NO_DAW = -1;
+const NOTICE_UNSUPPORTED_OPERATION = 1;
+const NOTICE_PERMISSION_DENIED = 11;
+const NOTE_NAMES = ['C', 'C#', 'D', 'D#', 'E', 'F', 'F#', 'G', 'G#', 'A', 'A#', 'B'];
+const PITCH_WHEEL_CENTER = 64;
+const RECONNECT_DELAY = 2000;
+// 服务端关闭后不要频繁重连，等它重新启动
+const RESTART_RECONNECT_DELAY = 10000;
+
+const $ = (id) => document.getElementById(id);
+const value = (id) => parseInt($(id).value, 10);
+
+let socket = null;
+let mode = 'note';
+let octave = 4;
+let wheelPos = PITCH_WHEEL_CENTER;
+// 本次连接是否建立成功、是否收到过服务端的消息，服务端开启配对时会在处理任何消息之前断开没有配对的连接
+let opened = false;
+let received = false;
+let shutdown = false;
+
+// 同名的客户端重连时，服务端会恢复它上次的设置，所以名字保存在本地
+function clientName() {
+  let name = localStorage.getItem('vpad-name');
+  if (!name) {
+    name = 'WebPad-' + Math.random().toString(16).slice(2, 6);
+    localStorage.setItem('vpad-name', name);
+  }
+  return name;
+}
+
+// 服务端开启配对时，二维码中的地址带有#pin=，配对成功后改为使用服务端发来的密钥
+function pairingToken() {
+  const key = localStorage.getItem('vpad-key');
+  if (key) return key;
+  const match = location.hash.match(/pin=(\d+)/);
+  return match ? match[1] : null;
+}
+
+function handshake() {
+  const body = [...str(clientName()), ...str('Web')];
+  const token = pairingToken();
+  if (token) body.push(NO_DAW, ...str(token));
+  send(OP.HANDSHAKE, body);
+}
+
+function str(text) {
+  const bytes = Array.from(new TextEncoder().encode(text)).slice(0, 255);
+  return [bytes.length, ...bytes];
+}
+
+function int2(n) {
+  return [(n >> 8) & 0xFF, n & 0xFF];
+}
+
+// content_bytes包括op本身
+function send(op, body) {
+  if (!socket || socket.readyState !== WebSocket.OPEN) return;
+  const length = body.length + 1;
+  socket.send(new Uint8Array([...int2(length), op, ...body.map((b) => b & 0xFF)]));
+}
+
+function connect() {
+  socket = new WebSocket(`ws://${location.host}/`);
+  socket.binaryType = 'arraybuffer';
+  opened = false;
+  received = false;
+  shutdown = false;
+  socket.onopen = () => {
+    opened = true;
+    handshake();
+    // 主动Ping一次，服务端之后才会给这个连接发送心跳
+    send(OP.PING, int2(0));
+    setStatus('Connected as ' + clientName(), true);
+  };
+  socket.onclose = () => {
+    // 服务端连接建立后会立即发送Ping，什么都没收到就被断开说明没有配对
+    if (opened && !received && pairingToken() !== null) {
+      localStorage.removeItem('vpad-key');
+      setStatus('Pairing failed, scan the QR code again', false);
+      return;
+    }
+    if (shutdown) {
+      setStatus('Server has shut down, waiting for it to restart...', false);
+      setTimeout(connect, RESTART_RECONNECT_DELAY);
+      return;
+    }
+    setStatus('Disconnected, reconnecting...', false);
+    setTimeout(connect, RECONNECT_DELAY);
+  };
+  socket.onmessage = (event) => onMessage(new Uint8Array(event.data));
+}
+
+// 服务端发出的每个WebSocket帧只包含一条消息
+function onMessage(bytes) {
+  if (bytes.length < 3) return;
+  received = true;
+  const op = bytes[2];
+  const body = bytes.slice(3);
+  switch (op) {
+    case OP.PING:
+      send(OP.PONG, [body[0], body[1]]);
+      break;
+    case OP.PAIR: {
+      const key = new TextDecoder().decode(body.slice(1, 1 + body[0]));
+      localStorage.setItem('vpad-key', key);
+      // PIN只用一次，从地址栏中去掉
+      history.replaceState(null, '', location.pathname);
+      break;
+    }
+    case OP.SHUTDOWN:
+      shutdown = true;
+      break;
+    case OP.NOTICE: {
+      const [code, requestOp, operation] = body;
+      const text = new TextDecoder().decode(body.slice(4, 4 + body[3]));
+      $('notice').textContent = text;
+      if (code === NOTICE_UNSUPPORTED_OPERATION && requestOp === OP.CONTROL) {
+        document.querySelectorAll(`#transport [data-op="${operation}"]`).forEach((b) => { b.disabled = true; });
+      }
+      // 角色不允许控制走带时，整排走带按钮都不可用
+      if (code === NOTICE_PERMISSION_DENIED && requestOp === OP.CONTROL) {
+        document.querySelectorAll('#transport [data-op]').forEach((b) => { b.disabled = true; });
+      }
+      break;
+    }
+    default:
+      break;
+  }
+}
+
+function setStatus(text, connected) {
+  $('status').textContent = text;
+  $('status').classList.toggle('connected', connected);
+}
+
+function channel() {
+  return value('channel');
+}
+
+function noteOn(note) {
+  const velocity = value('velocity');
+  if (mode === 'arp') {
+    sendArp(note, velocity, 1);
+  } else if (mode === 'chord') {
+    sendChord(note, velocity, 1);
+  } else {
+    send(OP.MIDI, [note, velocity, 1, channel()]);
+  }
+}
+
+function noteOff(note, padMode) {
+  if (padMode === 'arp') {
+    sendArp(note, 0, 0);
+  } else if (padMode === 'chord') {
+    sendChord(note, 0, 0);
+  } else {
+    send(OP.MIDI, [note, 0, 0, channel()]);
+  }
+}
+
+function sendArp(note, velocity, state) {
+  send(OP.ARP, [
+    note, velocity, state,
+    value('arp-method'), value('arp-rate'), value('arp-swing'),
+    value('arp-up-note-cnt'), value('arp-velocity-automation'),
+    ...int2(value('arp-dynamic')), ...int2(value('bpm')),
+    channel(),
+  ]);
+}
+
+function sendChord(note, velocity, state) {
+  send(OP.CHORD, [
+    note, velocity, state,
+    value('chord-type'), value('chord-level'), value('chord-transpose'), value('chord-arp-delay'),
+    ...int2(value('bpm')),
+    channel(),
+  ]);
+}
+
+function buildPads() {
+  const pads = $('pads');
+  pads.innerHTML = '';
+  // 从下往上、从左往右排列，与常见的打击垫一致
+  for (let row = 3; row >= 0; row--) {
+    for (let col = 0; col < 4; col++) {
+      const note = octave * 12 + row * 4 + col;
+      const pad = document.createElement('div');
+      pad.className = 'pad';
+      pad.textContent = NOTE_NAMES[note % 12] + (Math.floor(note / 12) - 1);
+      let downMode = null;
+      const release = () => {
+        if (downMode === null) return;
+        noteOff(note, downMode);
+        downMode = null;
+        pad.classList.remove('down');
+      };
+      pad.addEventListener('pointerdown', (e) => {
+        pad.setPointerCapture(e.pointerId);
+        // 记录按下时的模式，松开前切换了模式也能正确松开
+        downMode = mode;
+        noteOn(note);
+        pad.classList.add('down');
+      });
+      pad.addEventListener('pointerup', release);
+      pad.addEventListener('pointercancel', release);
+      pads.appendChild(pad);
+    }
+  }
+}
+
+function moveWheel(pos) {
+  send(OP.PITCHWHEEL, [pos, wheelPos, channel()]);
+  wheelPos = pos;
+}
+
+function setup() {
+  for (let ch = 1; ch <= 16; ch++) {
+    $('channel').add(new Option(ch, ch));
+  }
+  $('octave-down').onclick = () => { octave = Math.max(0, octave - 1); buildPads(); };
+  $('octave-up').onclick = () => { octave = Math.min(9, octave + 1); buildPads(); };
+  $('panic').onclick = () => send(OP.PANIC, []);
+
+  document.querySelectorAll('#transport [data-op]').forEach((button) => {
+    button.onclick = () => send(OP.CONTROL, [parseInt(button.dataset.op, 10), 1, 1]);
+  });
+
+  document.querySelectorAll('#modes [data-mode]').forEach((button) => {
+    button.onclick = () => {
+      mode = button.dataset.mode;
+      document.querySelectorAll('#modes [data-mode]').forEach((b) => b.classList.toggle('active', b === button));
+      $('arp-panel').classList.toggle('visible', mode === 'arp');
+      $('chord-panel').classList.toggle('visible', mode === 'chord');
+    };
+  });
+
+  // 弯音轮松手后回弹到中间位置
+  const wheel = $('wheel');
+  wheel.addEventListener('input', () => moveWheel(parseInt(wheel.value, 10)));
+  const springBack = () => {
+    wheel.value = PITCH_WHEEL_CENTER;
+    moveWheel(PITCH_WHEEL_CENTER);
+  };
+  wheel.addEventListener('pointerup', springBack);
+  wheel.addEventListener('pointercancel', springBack);
+
+  buildPads();
+  connect();
+}
+
+setup();